@@ -0,0 +1,39 @@
+extern crate elsa;
+
+mod common;
+
+use std::hint::black_box;
+
+use elsa::arc_trie::ArcTrie;
+use elsa::trie::Trie;
+
+use common::bench;
+
+const ENTRIES: usize = 10_000;
+
+/// Value which is costly to clone
+#[derive(Clone)]
+struct Big {
+    fields: Vec<u64>,
+}
+
+fn big(index: usize) -> Big {
+    Big { fields: vec![index as u64; 256] }
+}
+
+fn main() {
+    let trie: Trie<Big> = (0..ENTRIES).map(|index| (index * 7919, big(index))).collect();
+    let arc_trie: ArcTrie<Big> = (0..ENTRIES).map(|index| (index * 7919, big(index))).collect();
+
+    bench("get every key of Trie<Big>", 100, || {
+        for index in 0..ENTRIES {
+            black_box(trie.get(index * 7919).map(|value| value.fields.len()));
+        }
+    });
+
+    bench("get every key of ArcTrie<Big>", 100, || {
+        for index in 0..ENTRIES {
+            black_box(arc_trie.get(index * 7919).map(|value| value.fields.len()));
+        }
+    });
+}
@@ -0,0 +1,32 @@
+//! Timing helpers shared by the benchmarks
+//!
+//! The benchmarks run on stable without extra dependencies,
+//! so each one is a plain binary printing the mean time per round.
+
+#![allow(dead_code)]
+
+use std::hint::black_box;
+use std::time::Instant;
+
+/// Run `f` for `rounds` times and print the mean time of a round
+pub fn bench<R, F: FnMut() -> R>(name: &str, rounds: u32, mut f: F) {
+    let start = Instant::now();
+
+    for _ in 0..rounds {
+        black_box(f());
+    }
+
+    println!("{:<48} {:>12.2?}", name, start.elapsed() / rounds);
+}
+
+/// Xorshift generator, so the inputs are the same on every run
+pub struct Rng(pub u64);
+
+impl Rng {
+    pub fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+}
@@ -0,0 +1,19 @@
+extern crate elsa;
+
+mod common;
+
+use elsa::trie::Trie;
+
+use common::{bench, Rng};
+
+fn main() {
+    let trie: Trie<usize> = (0..100_000).map(|index| (index * 3, index)).collect();
+    let mut rng = Rng(42);
+    let start = (rng.next() % 200_000) as usize;
+    let keys: Vec<_> = (start..start + 10_000).collect();
+
+    bench("contains_key of 10k adjacent keys", 100, || {
+        keys.iter().map(|&key| trie.contains_key(key)).collect::<Vec<_>>()
+    });
+    bench("contains_sorted of 10k adjacent keys", 100, || trie.contains_sorted(keys.iter().cloned()));
+}
@@ -0,0 +1,22 @@
+extern crate elsa;
+
+mod common;
+
+use elsa::trie::Trie;
+
+use common::bench;
+
+const START: usize = 0x5a5a_5000;
+const COUNT: usize = 10_000;
+
+fn main() {
+    let base: Trie<usize> = (0..100_000).map(|index| (index * 7919, index)).collect();
+
+    bench("update 10000 contiguous keys one by one", 20, || {
+        (START..START + COUNT).fold(base.clone(), |trie, index| trie.update(index, 0))
+    });
+
+    bench("fill_range of 10000 contiguous keys", 20, || {
+        base.fill_range(START, COUNT, 0)
+    });
+}
@@ -0,0 +1,25 @@
+extern crate elsa;
+
+mod common;
+
+use elsa::trie::Trie;
+
+use common::{bench, Rng};
+
+const ENTRIES: usize = 10_000_000;
+
+fn main() {
+    let mut rng = Rng(0x5eed);
+    let mut keys: Vec<_> = (0..ENTRIES).map(|_| rng.next() as usize).collect();
+    keys.sort_unstable();
+    keys.dedup();
+    let entries: Vec<_> = keys.into_iter().map(|key| (key, key)).collect();
+
+    bench("from_sorted_slice of 10M entries", 3, || {
+        Trie::from_sorted_slice(&entries)
+    });
+
+    bench("from_sorted_parallel of 10M entries", 3, || {
+        Trie::from_sorted_parallel(&entries)
+    });
+}
@@ -0,0 +1,16 @@
+extern crate elsa;
+
+mod common;
+
+use elsa::trie::Trie;
+
+use common::{bench, Rng};
+
+fn main() {
+    let mut rng = Rng(42);
+    let trie: Trie<u64> = (0..100_000).map(|value| (rng.next() as usize, value)).collect();
+    let keys: Vec<_> = trie.iter().map(|(key, _)| key).step_by(3).collect();
+
+    bench("remove_all of 1/3 of 100k keys", 20, || trie.remove_all(keys.iter().cloned()));
+    bench("remove_sorted of 1/3 of 100k keys", 20, || trie.remove_sorted(keys.iter().cloned()));
+}
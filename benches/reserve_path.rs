@@ -0,0 +1,34 @@
+extern crate elsa;
+
+mod common;
+
+use elsa::trie::Trie;
+
+use common::bench;
+
+const PREFIX: usize = 0x5a5a_5000;
+
+fn main() {
+    let base: Trie<usize> = (0..100_000).map(|index| (index * 7919, index)).collect();
+
+    bench("update 1000 keys under a prefix", 100, || {
+        let mut trie_mut = base.to_mut();
+
+        for index in 0..1000 {
+            trie_mut.insert(PREFIX + index, index);
+        }
+
+        trie_mut.into_trie()
+    });
+
+    bench("update 1000 keys under a reserved prefix", 100, || {
+        let mut trie_mut = base.to_mut();
+        trie_mut.reserve_path(PREFIX);
+
+        for index in 0..1000 {
+            trie_mut.insert(PREFIX + index, index);
+        }
+
+        trie_mut.into_trie()
+    });
+}
@@ -0,0 +1,24 @@
+extern crate elsa;
+
+mod common;
+
+use elsa::trie::{Trie, TrieMut};
+
+use common::{bench, Rng};
+
+fn main() {
+    let mut rng = Rng(42);
+    let keys: Vec<_> = (0..100_000).map(|_| rng.next() as usize).collect();
+
+    bench("insert 100k uniform keys", 20, || {
+        let mut trie_mut = Trie::new().to_mut();
+        trie_mut.extend(keys.iter().map(|&key| (key, key)));
+        trie_mut.into_trie()
+    });
+
+    bench("insert 100k uniform keys with root capacity", 20, || {
+        let mut trie_mut = TrieMut::new_with_root_capacity(32);
+        trie_mut.extend(keys.iter().map(|&key| (key, key)));
+        trie_mut.into_trie()
+    });
+}
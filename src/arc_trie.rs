@@ -0,0 +1,97 @@
+//! Tries which store their values behind `Arc`
+//!
+//! Reading a value from `ArcTrie` bumps a reference count instead of
+//! cloning the value, which pays off for large values read often,
+//! at the cost of a pointer chase on every access.
+
+use std::iter::FromIterator;
+use std::sync::Arc;
+
+use trie::{Iter, Trie};
+
+/// Array-mapped trie whose values are shared through `Arc`
+#[derive(Debug)]
+pub struct ArcTrie<T> {
+    trie: Trie<Arc<T>>,
+}
+
+impl<T> ArcTrie<T> {
+    pub fn new() -> Self {
+        ArcTrie::from_trie(Trie::new())
+    }
+
+    fn from_trie(trie: Trie<Arc<T>>) -> Self {
+        ArcTrie { trie }
+    }
+
+    pub fn len(&self) -> usize {
+        self.trie.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.trie.is_empty()
+    }
+
+    /// Shared handle to the value at `index`, without cloning the value
+    pub fn get(&self, index: usize) -> Option<Arc<T>> {
+        self.trie.get(index)
+    }
+
+    pub fn get_ref(&self, index: usize) -> Option<&T> {
+        self.trie.get_ref(index).map(|value| &**value)
+    }
+
+    pub fn contains_key(&self, index: usize) -> bool {
+        self.trie.contains_key(index)
+    }
+
+    pub fn update(&self, index: usize, value: T) -> Self {
+        self.update_arc(index, Arc::new(value))
+    }
+
+    /// Store an already shared value at `index`
+    pub fn update_arc(&self, index: usize, value: Arc<T>) -> Self {
+        ArcTrie::from_trie(self.trie.update(index, value))
+    }
+
+    pub fn remove(&self, index: usize) -> Self {
+        ArcTrie::from_trie(self.trie.remove(index))
+    }
+
+    pub fn iter(&self) -> Iter<'_, usize, Arc<T>> {
+        self.trie.iter()
+    }
+
+    /// Underlying trie of shared values, for the rest of the `Trie` API
+    pub fn as_trie(&self) -> &Trie<Arc<T>> {
+        &self.trie
+    }
+}
+
+impl<T> Default for ArcTrie<T> {
+    fn default() -> Self {
+        ArcTrie::new()
+    }
+}
+
+impl<T> Clone for ArcTrie<T> {
+    fn clone(&self) -> Self {
+        ArcTrie::from_trie(self.trie.clone())
+    }
+}
+
+impl<T: PartialEq> PartialEq for ArcTrie<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.trie == other.trie
+    }
+}
+
+impl<T: Eq> Eq for ArcTrie<T> {}
+
+impl<T> FromIterator<(usize, T)> for ArcTrie<T> {
+    fn from_iter<I: IntoIterator<Item=(usize, T)>>(iter: I) -> Self {
+        ArcTrie::from_trie(iter.into_iter()
+            .map(|(index, value)| (index, Arc::new(value)))
+            .collect())
+    }
+}
@@ -1,7 +1,53 @@
+//! Fixed-size bitset which tracks occupied child positions of a trie node
+
 use std::num::Wrapping;
-use std::usize;
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+// Keys are split into 5-bit chunks of `usize`, and only the layouts
+// of 32-bit and 64-bit targets are checked below.
+#[cfg(not(any(target_pointer_width = "32", target_pointer_width = "64")))]
+compile_error!("Elsa only supports 32-bit and 64-bit targets");
+
+pub(crate) const USIZE_BITS: usize = usize::BITS as usize;
+pub(crate) const MAX_DEPTH: usize = max_depth(USIZE_BITS);
+
+const fn max_depth(bits: usize) -> usize {
+    (bits - 1) / 5 + 1
+}
+
+const fn chunk_shift(bits: usize, depth: usize) -> usize {
+    bits.saturating_sub(depth * 5).saturating_sub(5)
+}
+
+const fn chunk_width(bits: usize, depth: usize) -> usize {
+    bits.saturating_sub(depth * 5) - chunk_shift(bits, depth)
+}
+
+/// Every depth must consume 1 ~ 5 bits right below the previous one,
+/// and nothing may be left for the depth past the last.
+const fn is_valid_layout(bits: usize) -> bool {
+    let mut depth = 0;
+    let mut end = bits;
+
+    while depth < max_depth(bits) {
+        let width = chunk_width(bits, depth);
+
+        if width == 0 || width > 5 || chunk_shift(bits, depth) + width != end {
+            return false;
+        }
+
+        end = chunk_shift(bits, depth);
+        depth += 1;
+    }
+
+    end == 0 && chunk_width(bits, max_depth(bits)) == 0
+}
+
+const _: () = assert!(is_valid_layout(32));
+const _: () = assert!(is_valid_layout(64));
+const _: () = assert!(is_valid_layout(USIZE_BITS));
+
+/// Set of positions within 0 ~ 31
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
 pub struct Bitset(Wrapping<u32>);
 
 /// Index within 0 ~ 31
@@ -9,13 +55,23 @@ pub struct Bitset(Wrapping<u32>);
 pub struct Index32(usize);
 
 impl Index32 {
+    /// Panics if `index` is 32 or more
     pub fn new(index: usize) -> Self {
-        debug_assert!(index < 32, "Bitset can hold 0 ~ 31, but received {}", index);
+        assert!(index < 32, "Bitset can hold 0 ~ 31, but received {}", index);
         Index32(index)
     }
 
+    /// Chunk of `index` consumed at `depth`, starting from the most significant bits
+    ///
+    /// Depths past the last one have no bits left and always yield 0.
     pub fn convert(index: usize, depth: usize) -> Self {
-        Index32((index >> (depth * 5)) & 0b11111)
+        let width = chunk_width(USIZE_BITS, depth);
+        Index32((index >> Index32::shift(depth)) & ((1 << width) - 1))
+    }
+
+    /// Number of bits below the chunk consumed at `depth`
+    pub fn shift(depth: usize) -> usize {
+        chunk_shift(USIZE_BITS, depth)
     }
 
     pub fn max_with(depth: usize) -> Self {
@@ -30,6 +86,9 @@ impl Index32 {
 const W0: Wrapping<u32> = Wrapping(0);
 const W1: Wrapping<u32> = Wrapping(1);
 
+#[cfg(test)]
+mod tests;
+
 impl Bitset {
     pub fn new() -> Self {
         Bitset(W0)
@@ -50,23 +109,85 @@ impl Bitset {
         prev
     }
 
-    // NOTE: maybe used later?
-    // pub fn unset(&mut self, index: Index32) -> bool {
-    //     let prev = self.get(index);
-    //     self.0 &= !(W1 << index.num());
-    //
-    //     prev
-    // }
+    pub fn unset(&mut self, index: Index32) -> bool {
+        let prev = self.get(index);
+        self.0 &= !(W1 << index.num());
+
+        prev
+    }
+
+    pub fn toggle(&mut self, index: Index32) -> bool {
+        let prev = self.get(index);
+        self.0 ^= W1 << index.num();
+
+        prev
+    }
+
+    pub fn with_set(mut self, index: Index32) -> Bitset {
+        self.set(index);
+        self
+    }
+
+    pub fn with_unset(mut self, index: Index32) -> Bitset {
+        self.unset(index);
+        self
+    }
 
     pub fn packed_index(&self, index: Index32) -> Option<usize> {
         if !self.get(index) {
             return None;
         }
-        let index = index.num();
 
-        let leadings_mask = !(W1 << (index + 1) - 1);
-        let leadings_count = (self.0 & leadings_mask).0.count_ones();
-        Some(leadings_count as usize)
+        Some(self.count_below(index))
+    }
+
+    /// Number of set positions strictly below `index`
+    ///
+    /// This is the rank of `index` among the set positions, which is
+    /// where its entry lives in a packed array.
+    pub fn count_below(&self, index: Index32) -> usize {
+        (self.0 & below_mask(index)).0.count_ones() as usize
+    }
+
+    /// Lowest set position
+    pub fn first_set(&self) -> Option<Index32> {
+        match self.num() {
+            0 => None,
+            bits => Some(Index32(bits.trailing_zeros() as usize)),
+        }
+    }
+
+    /// Highest set position
+    pub fn last_set(&self) -> Option<Index32> {
+        match self.num() {
+            0 => None,
+            bits => Some(Index32(31 - bits.leading_zeros() as usize)),
+        }
+    }
+
+    /// Positions strictly below `index`
+    pub fn mask_below(&self, index: Index32) -> Bitset {
+        Bitset(self.0 & below_mask(index))
+    }
+
+    /// Positions strictly above `index`
+    pub fn mask_above(&self, index: Index32) -> Bitset {
+        Bitset(self.0 & !(below_mask(index) | W1 << index.num()))
+    }
+
+    /// Positions within `lo ..= hi`
+    pub fn mask_between(&self, lo: Index32, hi: Index32) -> Bitset {
+        Bitset(self.0 & !below_mask(lo) & (below_mask(hi) | W1 << hi.num()))
+    }
+
+    /// Positions set in `self` but not in `other`
+    pub fn only_in(&self, other: Bitset) -> Bitset {
+        Bitset(self.0 & !other.0)
+    }
+
+    /// Iterator over the positions set in `self` but not in `other`
+    pub fn difference_iter(&self, other: Bitset) -> BitsetIter {
+        self.only_in(other).iter()
     }
 
     pub fn iter(&self) -> BitsetIter {
@@ -74,6 +195,29 @@ impl Bitset {
     }
 }
 
+impl From<u32> for Bitset {
+    fn from(bits: u32) -> Self {
+        Bitset(Wrapping(bits))
+    }
+}
+
+impl From<Bitset> for u32 {
+    fn from(bitset: Bitset) -> Self {
+        bitset.num()
+    }
+}
+
+impl PartialEq<u32> for Bitset {
+    fn eq(&self, other: &u32) -> bool {
+        self.num() == *other
+    }
+}
+
+fn below_mask(index: Index32) -> Wrapping<u32> {
+    (W1 << index.num()) - W1
+}
+
+/// Iterator over the set positions of a `Bitset` in ascending order
 #[derive(Debug, Clone, Copy)]
 pub struct BitsetIter(Bitset);
 
@@ -81,12 +225,9 @@ impl Iterator for BitsetIter {
     type Item = Index32;
 
     fn next(&mut self) -> Option<Index32> {
-        let count = self.0.num().leading_zeros();
+        let first = self.0.first_set()?;
+        (self.0).0 &= !(W1 << first.num());
 
-        if count == 32 {
-            None
-        } else {
-            Some(Index32(count as usize))
-        }
+        Some(first)
     }
 }
@@ -0,0 +1,91 @@
+use super::{Bitset, Index32, USIZE_BITS};
+use super::{chunk_shift, chunk_width, is_valid_layout, max_depth};
+
+#[test]
+fn chunks_start_from_the_most_significant_bits() {
+    let top = 1 << (USIZE_BITS - 1);
+
+    assert_eq!(Index32::shift(0), USIZE_BITS - 5);
+    assert_eq!(Index32::convert(top, 0).num(), 0b10000);
+    assert_eq!(Index32::convert(top, 1).num(), 0);
+    assert_eq!(Index32::convert(1, 0).num(), 0);
+}
+
+#[test]
+fn chunks_reassemble_the_index() {
+    let last = USIZE_BITS.div_ceil(5) - 1;
+
+    for &index in [0, 1, 12345, 1 << 31, usize::MAX, usize::MAX / 3].iter() {
+        let rebuilt = (0..last + 1).fold(0, |acc, depth| {
+            acc | Index32::convert(index, depth).num() << Index32::shift(depth)
+        });
+
+        assert_eq!(rebuilt, index);
+    }
+
+    assert_eq!(Index32::shift(last), 0);
+    assert_eq!(Index32::max_with(last).num(), (1 << (USIZE_BITS - last * 5)) - 1);
+}
+
+#[test]
+fn packed_index_counts_lower_positions() {
+    let mut bitset = Bitset::new();
+
+    for &index in [1, 4, 5, 31].iter() {
+        bitset.set(Index32::new(index));
+    }
+
+    assert_eq!(bitset.packed_index(Index32::new(1)), Some(0));
+    assert_eq!(bitset.packed_index(Index32::new(4)), Some(1));
+    assert_eq!(bitset.packed_index(Index32::new(5)), Some(2));
+    assert_eq!(bitset.packed_index(Index32::new(31)), Some(3));
+    assert_eq!(bitset.packed_index(Index32::new(0)), None);
+    assert_eq!(bitset.packed_index(Index32::new(30)), None);
+}
+
+#[test]
+fn iter_yields_ascending_positions() {
+    let mut bitset = Bitset::new();
+
+    for &index in [31, 0, 17, 2].iter() {
+        bitset.set(Index32::new(index));
+    }
+
+    let positions: Vec<_> = bitset.iter().map(|idx32| idx32.num()).collect();
+    assert_eq!(positions, vec![0, 2, 17, 31]);
+    assert_eq!(Bitset::new().iter().next(), None);
+}
+
+#[test]
+fn both_target_widths_have_valid_layouts() {
+    assert_eq!(max_depth(32), 7);
+    assert_eq!(max_depth(64), 13);
+    assert!(is_valid_layout(32));
+    assert!(is_valid_layout(64));
+}
+
+#[test]
+fn last_chunk_is_partial_on_both_widths() {
+    assert_eq!(chunk_width(32, 6), 2);
+    assert_eq!(chunk_shift(32, 6), 0);
+    assert_eq!(chunk_width(64, 12), 4);
+    assert_eq!(chunk_shift(64, 12), 0);
+}
+
+#[test]
+fn chunks_tile_every_bit_on_both_widths() {
+    for &bits in [32, 64].iter() {
+        let mut covered = 0u64;
+
+        for depth in 0..max_depth(bits) {
+            let chunk = ((1u64 << chunk_width(bits, depth)) - 1) << chunk_shift(bits, depth);
+
+            assert_eq!(covered & chunk, 0);
+            covered |= chunk;
+        }
+
+        assert_eq!(covered, u64::MAX >> (64 - bits));
+        assert_eq!(chunk_width(bits, max_depth(bits)), 0);
+        assert_eq!(chunk_width(bits, max_depth(bits) + 3), 0);
+    }
+}
@@ -0,0 +1,50 @@
+//! Key types of tries other than `usize`
+//!
+//! Keys are shifted into the high bits of the `usize` index space,
+//! so they keep their order and a `KeyedTrie<u16, T>` is only
+//! 4 levels deep instead of spanning every level of `usize`.
+
+use std::fmt;
+use std::hash::Hash;
+
+use bitset::USIZE_BITS;
+
+pub use trie::{KeyedTrie, KeyedTrieMut};
+
+/// Unsigned integer type which can be used as the key of a `KeyedTrie`
+pub trait TrieKey: Copy + Ord + Hash + fmt::Debug + fmt::Display {
+    /// Number of bits in the key
+    const BITS: usize;
+
+    /// Map the key into the `usize` index space, preserving order
+    fn to_index(self) -> usize;
+
+    /// Inverse of `to_index`
+    fn from_index(index: usize) -> Self;
+
+    /// Maximum depth of the trie nodes for this key type
+    fn max_depth() -> usize {
+        (Self::BITS - 1) / 5 + 1
+    }
+}
+
+macro_rules! impl_trie_key {
+    ($($ty:ty),*) => {$(
+        impl TrieKey for $ty {
+            const BITS: usize = <$ty>::BITS as usize;
+
+            fn to_index(self) -> usize {
+                (self as usize) << (USIZE_BITS - <$ty as TrieKey>::BITS)
+            }
+
+            fn from_index(index: usize) -> Self {
+                (index >> (USIZE_BITS - <$ty as TrieKey>::BITS)) as $ty
+            }
+        }
+    )*};
+}
+
+impl_trie_key!(u8, u16, u32, usize);
+
+#[cfg(target_pointer_width = "64")]
+impl_trie_key!(u64);
@@ -1,3 +1,6 @@
 
-mod bitset;
+pub mod bitset;
 pub mod trie;
+pub mod keyed;
+pub mod overlay;
+pub mod arc_trie;
@@ -0,0 +1,74 @@
+//! Layered lookups over two tries without merging them
+
+use std::iter::Peekable;
+
+use keyed::TrieKey;
+use trie::{Iter, KeyedTrie};
+
+/// Read-only view where entries of `overlay` shadow those of `base`
+#[derive(Debug)]
+pub struct Overlay<'a, K: 'a, T: 'a> {
+    base: &'a KeyedTrie<K, T>,
+    overlay: &'a KeyedTrie<K, T>,
+}
+
+/// Iterator over the visible entries of an `Overlay` in ascending key order
+#[derive(Debug)]
+pub struct OverlayIter<'a, K: TrieKey + 'a, T: 'a> {
+    base: Peekable<Iter<'a, K, T>>,
+    overlay: Peekable<Iter<'a, K, T>>,
+}
+
+impl<'a, K: TrieKey, T: Clone> Overlay<'a, K, T> {
+    pub fn new(base: &'a KeyedTrie<K, T>, overlay: &'a KeyedTrie<K, T>) -> Self {
+        Overlay {
+            base,
+            overlay,
+        }
+    }
+
+    pub fn get(&self, key: K) -> Option<T> {
+        self.get_ref(key).cloned()
+    }
+
+    pub fn get_ref(&self, key: K) -> Option<&'a T> {
+        self.overlay.get_ref(key).or_else(|| self.base.get_ref(key))
+    }
+
+    pub fn contains_key(&self, key: K) -> bool {
+        self.overlay.contains_key(key) || self.base.contains_key(key)
+    }
+
+    /// Every visible key once, with the value from `overlay` if it has one
+    pub fn iter(&self) -> OverlayIter<'a, K, T> {
+        OverlayIter {
+            base: self.base.iter().peekable(),
+            overlay: self.overlay.iter().peekable(),
+        }
+    }
+}
+
+impl<'a, K: TrieKey, T> Iterator for OverlayIter<'a, K, T> {
+    type Item = (K, &'a T);
+
+    fn next(&mut self) -> Option<(K, &'a T)> {
+        let base = self.base.peek().map(|entry| entry.0);
+        let overlay = self.overlay.peek().map(|entry| entry.0);
+
+        match (base, overlay) {
+            (None, _) => self.overlay.next(),
+            (Some(_), None) => self.base.next(),
+            (Some(base), Some(overlay)) => {
+                if base < overlay {
+                    return self.base.next();
+                }
+
+                if base == overlay {
+                    self.base.next();
+                }
+
+                self.overlay.next()
+            }
+        }
+    }
+}
@@ -1,29 +1,168 @@
 //! Array-Mapped Trie implementation
 //!
 //! Provides O(log32 n) ~= O(1) index/update
+//!
+//! Only 32-bit and 64-bit targets are supported.
 
 use std::sync::Arc;
-use std::mem::size_of;
-use std::iter::FromIterator;
-
-use bitset::{Bitset, Index32};
-
-const MAX_DEPTH: usize = (size_of::<usize>() - 1) / 5 + 1;
+use std::fmt;
+use std::error::Error;
+use std::mem;
+use std::cmp::Ordering;
+use std::array;
+use std::slice;
+use std::vec;
+#[cfg(feature = "parallel")]
+use std::{panic, thread};
+use std::iter::{self, FromIterator, Peekable};
+use std::marker::PhantomData;
+use std::ops::{self, Bound, ControlFlow, RangeBounds};
+use std::convert::Infallible;
+use std::hash::{Hash, Hasher};
+use std::collections::{BTreeMap, HashMap, HashSet, TryReserveError};
+use std::collections::hash_map::DefaultHasher;
+
+use bitset::{Bitset, Index32, USIZE_BITS, MAX_DEPTH};
+use keyed::TrieKey;
+use overlay::Overlay;
 
 /// Array-Mapped Trie
 ///
 /// Array-mapped trie is a efficient implementation of sparse array
 /// which uses entire `usize` range as its index space.
+///
+/// Every traversal visits entries in ascending key order, regardless of
+/// the order they were inserted in or which subtrees are shared with
+/// other tries. Keys are routed from their highest chunk down and each
+/// node keeps its children in bitset order, so two tries with the same
+/// entries always iterate identically.
+///
+/// Keys can be any `TrieKey`, like `KeyedTrie<u32, T>` whose nodes are
+/// at most `u32::max_depth()` levels deep. `Trie<T>` keeps the `usize`
+/// keys of the original API.
 #[derive(Debug)]
-pub struct Trie<T> {
+pub struct KeyedTrie<K, T> {
     root: Option<Node<T>>,
     length: usize,
+    key: PhantomData<K>,
 }
 
+/// Array-mapped trie keyed by `usize`
+pub type Trie<T> = KeyedTrie<usize, T>;
+
 /// Variant for temporal mutation
 #[derive(Debug)]
-pub struct TrieMut<T> {
+pub struct KeyedTrieMut<K, T> {
     root: NodeMut<T>,
+    key: PhantomData<K>,
+}
+
+/// Mutation session of a `Trie` keyed by `usize`
+pub type TrieMut<T> = KeyedTrieMut<usize, T>;
+
+/// Iterator over the entries of a `Trie` in ascending key order
+#[derive(Debug)]
+pub struct Iter<'a, K, T: 'a> {
+    stack: Vec<slice::Iter<'a, Node<T>>>,
+    back: Vec<slice::Iter<'a, Node<T>>>,
+    remaining: usize,
+    key: PhantomData<K>,
+}
+
+/// Iterator over the entries of a `Trie` within a key range, in ascending order
+#[derive(Debug)]
+pub struct Range<'a, K, T: 'a> {
+    stack: Vec<slice::Iter<'a, Node<T>>>,
+    start: usize,
+    end: usize,
+    key: PhantomData<K>,
+}
+
+/// Lazy changes from one `Trie` to another, in ascending key order
+#[derive(Debug)]
+pub struct Diff<'a, K: TrieKey, T: 'a> {
+    pending: Vec<DiffStep<'a, K, T>>,
+}
+
+#[derive(Debug)]
+enum DiffStep<'a, K: TrieKey, T: 'a> {
+    Nodes(&'a Node<T>, &'a Node<T>),
+    Removed(Range<'a, K, T>),
+    Added(Range<'a, K, T>),
+    Merge(Peekable<Range<'a, K, T>>, Peekable<Range<'a, K, T>>),
+}
+
+/// Difference at a single key from one trie to another
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change<K, T> {
+    /// The key is only present in the other trie
+    Added(K, T),
+    /// The key is only present in this trie
+    Removed(K, T),
+    /// The key is present in both with different values, as `(key, old, new)`
+    Modified(K, T, T),
+}
+
+/// Owning iterator over the entries of a `Trie` in ascending key order
+#[derive(Debug)]
+pub struct IntoIter<K, T> {
+    stack: Vec<vec::IntoIter<Node<T>>>,
+    placeholder: Arc<[Node<T>]>,
+    remaining: usize,
+    key: PhantomData<K>,
+}
+
+/// Owned mutation session which visits every value of a `Trie`
+///
+/// Iterate over `&mut TrieIterMut` to mutate values in place,
+/// then commit all mutations at once with `into_trie`.
+#[derive(Debug)]
+pub struct TrieIterMut<K, T> {
+    trie: KeyedTrieMut<K, T>,
+}
+
+/// Iterator over the entries of a `TrieIterMut` in ascending key order
+#[derive(Debug)]
+pub struct IterMut<'a, K, T: 'a> {
+    root: Option<&'a mut NodeMut<T>>,
+    stack: Vec<slice::IterMut<'a, (Index32, NodeMut<T>)>>,
+    key: PhantomData<K>,
+}
+
+/// Error of `TrieMut::get_many_mut` for an index which can't be borrowed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AliasError<K> {
+    /// The index is requested more than once, so the borrows would alias
+    Duplicate(K),
+    /// The index is not present in the trie
+    Absent(K),
+}
+
+/// Hashes of inner nodes remembered across `Trie::merkle_root_with` calls
+///
+/// Entries are keyed by the allocation of their children, which the cache
+/// keeps alive so the address can't be reused by another node.
+#[derive(Debug)]
+pub struct MerkleCache<T> {
+    hashes: HashMap<usize, (Children<T>, u64)>,
+}
+
+/// Single operation of a `Trie::batch`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Op<K, T> {
+    Insert(K, T),
+    Remove(K),
+}
+
+/// Effect of a `Trie::update_checked`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpdateKind {
+    /// The key was absent
+    Inserted,
+    /// The key held a different value
+    Replaced,
+    /// The key already held an equal value, so nothing was rebuilt
+    Unchanged,
 }
 
 #[derive(Debug)]
@@ -35,9 +174,14 @@ enum Node<T> {
     More {
         bitset: Bitset,
         nodes: Arc<[Node<T>]>,
+        /// Number of entries below, kept so lengths don't need a walk
+        length: usize,
     },
 }
 
+/// Shared children of an inner node
+type Children<T> = Arc<[Node<T>]>;
+
 #[derive(Debug)]
 enum NodeMut<T> {
     Empty,
@@ -47,11 +191,25 @@ enum NodeMut<T> {
 
 use self::{Node::*, NodeMut::*};
 
-impl<T: Clone> Trie<T> {
+#[cfg(test)]
+mod tests;
+
+impl<K: TrieKey, T: Clone> KeyedTrie<K, T> {
     pub fn new() -> Self {
-        Trie {
+        KeyedTrie {
             root: None,
             length: 0,
+            key: PhantomData,
+        }
+    }
+
+    fn from_root(root: Option<Node<T>>) -> KeyedTrie<K, T> {
+        let length = root.as_ref().map_or(0, |node| node.len());
+
+        KeyedTrie {
+            root,
+            length,
+            key: PhantomData,
         }
     }
 
@@ -59,221 +217,3192 @@ impl<T: Clone> Trie<T> {
         self.length
     }
 
-    pub fn get(&self, index: usize) -> Option<T> {
-        self.root.as_ref().and_then(|node| node.get(index))
-    }
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+
+    pub fn get(&self, key: K) -> Option<T> {
+        self.get_ref(key).cloned()
+    }
+
+    pub fn get_ref(&self, key: K) -> Option<&T> {
+        self.root.as_ref().and_then(|node| node.get(0, key.to_index()))
+    }
+
+    pub fn contains_key(&self, key: K) -> bool {
+        self.get_ref(key).is_some()
+    }
+
+    /// Entries in ascending key order
+    pub fn iter(&self) -> Iter<'_, K, T> {
+        let stack: Vec<_> = self.root.as_ref().map(|node| slice::from_ref(node).iter()).into_iter().collect();
+
+        Iter {
+            back: stack.clone(),
+            stack,
+            remaining: self.length,
+            key: PhantomData,
+        }
+    }
+
+    /// Owned entries in ascending key order
+    ///
+    /// Values are moved out of the nodes owned only by `self`,
+    /// so only the nodes shared with other tries get cloned.
+    pub fn into_iter_sorted(self) -> IntoIter<K, T> {
+        IntoIter {
+            stack: self.root.map(|node| vec![node].into_iter()).into_iter().collect(),
+            placeholder: Arc::from(Vec::new()),
+            remaining: self.length,
+            key: PhantomData,
+        }
+    }
+
+    /// Entries whose keys are within `range`, in ascending key order
+    ///
+    /// Only the path to the start of the range is descended,
+    /// so the subtrees entirely below it are never visited.
+    pub fn range<R: RangeBounds<K>>(&self, range: R) -> Range<'_, K, T> {
+        let indices = (
+            range.start_bound().map(|key| key.to_index()),
+            range.end_bound().map(|key| key.to_index()),
+        );
+
+        match (self.root.as_ref(), bounds(&indices)) {
+            (Some(node), Some((start, end))) => node.range(start, end).with_key(),
+            _ => Range {
+                stack: Vec::new(),
+                start: 0,
+                end: 0,
+                key: PhantomData,
+            },
+        }
+    }
+
+    pub fn iter_mut(&self) -> TrieIterMut<K, T> {
+        let mut trie = self.to_mut();
+        trie.root.make_mut_all();
+
+        TrieIterMut { trie }
+    }
+
+    pub fn to_mut(&self) -> KeyedTrieMut<K, T> {
+        KeyedTrieMut {
+            root: match self.root {
+                Some(ref node) => Imut(node.clone()),
+                None => Empty,
+            },
+            key: PhantomData,
+        }
+    }
+
+    pub fn update_all<I: IntoIterator<Item=(K, T)>>(&self, iter: I) -> Self {
+        let mut iter = iter.into_iter();
+
+        match iter.next() {
+            None => self.clone(),
+            Some((key, value)) => {
+                let mut trie_mut = self.to_mut();
+                trie_mut.insert(key, value);
+
+                for (key, value) in iter {
+                    trie_mut.insert(key, value);
+                }
+
+                trie_mut.into_trie()
+            }
+        }
+    }
+
+    pub fn update(&self, key: K, value: T) -> Self {
+        let mut trie_mut = self.to_mut();
+        trie_mut.insert(key, value);
+        trie_mut.into_trie()
+    }
+
+    pub fn remove_all<I: IntoIterator<Item=K>>(&self, iter: I) -> Self {
+        let mut iter = iter.into_iter();
+
+        match iter.next() {
+            None => self.clone(),
+            Some(key) => {
+                let mut trie_mut = self.to_mut();
+                trie_mut.remove(key);
+
+                for key in iter {
+                    trie_mut.remove(key);
+                }
+
+                trie_mut.into_trie()
+            }
+        }
+    }
+
+    pub fn remove(&self, key: K) -> Self {
+        let mut trie_mut = self.to_mut();
+        trie_mut.remove(key);
+        trie_mut.into_trie()
+    }
+
+    pub fn min_key(&self) -> Option<K> {
+        self.root.as_ref().map(|node| K::from_index(node.min_key()))
+    }
+
+    pub fn max_key(&self) -> Option<K> {
+        self.root.as_ref().map(|node| K::from_index(node.max_key()))
+    }
+
+    /// Entries for which `f` returns `true`
+    ///
+    /// Subtrees in which every entry is kept stay shared with `self`.
+    pub fn retain<F: FnMut(K, &T) -> bool>(&self, f: F) -> KeyedTrie<K, T> {
+        self.retain_counting(f).0
+    }
+
+    /// Entries for which `f` returns `true`, and the number of entries dropped
+    pub fn retain_counting<F: FnMut(K, &T) -> bool>(&self, mut f: F) -> (KeyedTrie<K, T>, usize) {
+        let mut by_index = |index, value: &T| f(K::from_index(index), value);
+        let mut removed = 0;
+
+        match self.root.as_ref().and_then(|node| node.retain(&mut by_index, &mut removed)) {
+            None => (self.clone(), 0),
+            Some(root) => {
+                let trie = KeyedTrie {
+                    root,
+                    length: self.length - removed,
+                    key: PhantomData,
+                };
+
+                (trie, removed)
+            }
+        }
+    }
+
+    /// Check the structural invariants of the trie
+    ///
+    /// Every key must be routed along its own chunks, every inner node must
+    /// hold children which match its bitset, inner nodes with a single leaf
+    /// must be collapsed into that leaf, and the cached lengths must match.
+    pub fn validate(&self) -> bool {
+        match self.root {
+            None => self.length == 0,
+            Some(ref node) => node.validate(0, 0) == Some(self.length),
+        }
+    }
+
+    /// Number of entries stored at each depth, from the root at 0 down to `K::max_depth()`
+    ///
+    /// Keys which differ in their high bits branch off near the root,
+    /// while keys sharing a long high-order prefix like `0..32` sink
+    /// toward the bottom levels.
+    pub fn depth_histogram(&self) -> Vec<usize> {
+        let mut histogram = Vec::new();
+
+        if let Some(ref node) = self.root {
+            node.depth_histogram(0, &mut histogram);
+        }
+
+        histogram
+    }
+
+    /// Build from entries sorted by strictly ascending keys
+    ///
+    /// Each node is built directly from the run of entries sharing its
+    /// prefix, which is faster than inserting entries one by one.
+    pub fn from_sorted_slice(entries: &[(K, T)]) -> KeyedTrie<K, T> {
+        debug_assert!(entries.windows(2).all(|pair| pair[0].0 < pair[1].0),
+            "Entries should be sorted by strictly ascending keys");
+
+        KeyedTrie {
+            root: build_sorted(0, entries),
+            length: entries.len(),
+            key: PhantomData,
+        }
+    }
+
+    /// Build from the `(start, length, value)` runs of `run_length_encode`
+    ///
+    /// Each run stores clones of its value at `length` consecutive keys from
+    /// `start`. The runs must be in ascending order without overlapping,
+    /// which is only checked in debug builds.
+    pub fn from_run_length<I: IntoIterator<Item=(K, usize, T)>>(runs: I) -> KeyedTrie<K, T> {
+        let step = key_step::<K>();
+        let mut entries = Vec::new();
+
+        for (start, length, value) in runs {
+            if let Some(offset) = length.checked_sub(1) {
+                let last = offset.checked_mul(step)
+                    .and_then(|offset| start.to_index().checked_add(offset))
+                    .unwrap_or_else(|| panic!("Run of {} keys from {} overflows the key type", length, start));
+
+                entries.extend((start.to_index()..=last).step_by(step).map(|index| (index, value.clone())));
+            }
+        }
+
+        KeyedTrie::from_root(build_sorted(0, &entries))
+    }
+
+    /// Build from entries sorted by strictly ascending keys, in parallel
+    ///
+    /// The entries are partitioned by the top-level chunk of their keys,
+    /// and each top-level subtree is built on its own scoped thread.
+    /// This needs the `parallel` feature, and uses `std::thread`
+    /// instead of a thread pool.
+    #[cfg(feature = "parallel")]
+    pub fn from_sorted_parallel(entries: &[(K, T)]) -> KeyedTrie<K, T> where K: Sync, T: Send + Sync {
+        debug_assert!(entries.windows(2).all(|pair| pair[0].0 < pair[1].0),
+            "Entries should be sorted by strictly ascending keys");
+
+        if entries.len() < 2 {
+            return KeyedTrie::from_sorted_slice(entries);
+        }
+
+        let mut bitset = Bitset::new();
+        let mut runs = Vec::new();
+        let mut rest = entries;
+
+        while let Some(&(key, _)) = rest.first() {
+            let idx32 = Index32::convert(key.to_index(), 0);
+            let run = rest.iter()
+                .take_while(|entry| Index32::convert(entry.0.to_index(), 0) == idx32)
+                .count();
+
+            bitset.set(idx32);
+            runs.push(&rest[..run]);
+            rest = &rest[run..];
+        }
+
+        let nodes: Vec<_> = thread::scope(|scope| {
+            let handles: Vec<_> = runs.into_iter()
+                .map(|run| scope.spawn(move || build_sorted(1, run).unwrap()))
+                .collect();
+
+            handles.into_iter()
+                .map(|handle| handle.join().unwrap_or_else(|err| panic::resume_unwind(err)))
+                .collect()
+        });
+
+        KeyedTrie {
+            root: Some(Node::more(bitset, Arc::from(nodes))),
+            length: entries.len(),
+            key: PhantomData,
+        }
+    }
+
+    /// Rebuild with every key transformed by `f`
+    ///
+    /// `f` must be strictly increasing, like adding an offset without
+    /// overflow, so the transformed keys stay in order and the trie can
+    /// be built bottom-up. This is only checked in debug builds.
+    pub fn map_keys<F: Fn(K) -> K>(&self, f: F) -> KeyedTrie<K, T> {
+        let entries: Vec<_> = self.iter()
+            .map(|(index, value)| (f(index), value.clone()))
+            .collect();
+
+        KeyedTrie::from_sorted_slice(&entries)
+    }
+
+    /// Merge each entry into the previous one when `f` combines them
+    ///
+    /// Walking the keys in ascending order, `f` receives the value kept so
+    /// far and the next one, and returns the merged value if they combine.
+    /// A merged run is stored at the key of its first entry.
+    pub fn coalesce<F: Fn(&T, &T) -> Option<T>>(&self, f: F) -> KeyedTrie<K, T> {
+        let mut entries: Vec<(K, T)> = Vec::new();
+
+        for (key, value) in self.iter() {
+            let merged = entries.last().and_then(|last| f(&last.1, value));
+
+            match merged {
+                Some(merged) => entries.last_mut().unwrap().1 = merged,
+                None => entries.push((key, value.clone())),
+            }
+        }
+
+        KeyedTrie::from_sorted_slice(&entries)
+    }
+
+    /// Address of the value stored at `key`, for caches keyed on value identity
+    ///
+    /// Leaves below inner nodes live in shared allocations, so clones of the
+    /// trie report the same address until the leaf is replaced. The only
+    /// entry of a single-entry trie is stored inline and moves with it.
+    /// The pointer is only valid to dereference while some trie holding
+    /// the leaf is alive, and it should only be compared otherwise.
+    pub fn leaf_ptr(&self, key: K) -> Option<*const T> {
+        self.get_ref(key).map(|value| value as *const T)
+    }
+
+    /// Value at `key`, or `T::default()` if absent
+    pub fn get_or_default(&self, key: K) -> T where T: Default {
+        self.get_ref(key).cloned().unwrap_or_default()
+    }
+
+    /// Trie containing `key` and the value stored there, inserting `T::default()` if absent
+    ///
+    /// When the key is already present the returned trie is a clone
+    /// of `self`, sharing its root.
+    pub fn get_or_insert_default(&self, key: K) -> (KeyedTrie<K, T>, T) where T: Default {
+        match self.get_ref(key) {
+            Some(value) => (self.clone(), value.clone()),
+            None => (self.update(key, T::default()), T::default()),
+        }
+    }
+
+    /// View which reads from `overlay` first and falls back to `self`
+    pub fn overlay<'a>(&'a self, overlay: &'a KeyedTrie<K, T>) -> Overlay<'a, K, T> {
+        Overlay::new(self, overlay)
+    }
+
+    /// Whether every key is present, stopping at the first absent one
+    pub fn contains_all<I: IntoIterator<Item=K>>(&self, keys: I) -> bool {
+        keys.into_iter().all(|key| self.contains_key(key))
+    }
+
+    /// Membership of each key given in ascending order
+    ///
+    /// Adjacent keys share the descent toward their common prefix, so a
+    /// batch of nearby keys is much cheaper than calling `contains_key`
+    /// for each. Unsorted keys are only detected in debug builds.
+    pub fn contains_sorted<I: IntoIterator<Item=K>>(&self, keys: I) -> Vec<bool> {
+        let mut keys = assert_sorted(keys.into_iter().map(K::to_index)).peekable();
+        let mut res = Vec::new();
+
+        if let Some(ref node) = self.root {
+            node.contains_sorted(0, 0, &mut keys, &mut res);
+        }
+
+        res.extend(keys.map(|_| false));
+        res
+    }
+
+    /// Whether any key is present, stopping at the first present one
+    pub fn contains_any<I: IntoIterator<Item=K>>(&self, keys: I) -> bool {
+        keys.into_iter().any(|key| self.contains_key(key))
+    }
+
+    /// Cloned entries in ascending key order, keeping `self` borrowed
+    pub fn cloned_pairs(&self) -> impl ExactSizeIterator<Item=(K, T)> + '_ {
+        self.iter().map(|(key, value)| (key, value.clone()))
+    }
+
+    /// Cloned entries collected into a `HashMap` sized for `len()` entries
+    pub fn to_hashmap(&self) -> HashMap<K, T> {
+        let mut map = HashMap::with_capacity(self.length);
+        map.extend(self.cloned_pairs());
+        map
+    }
+
+    /// Cloned entries collected into a `BTreeMap`, inserted in ascending key order
+    pub fn to_btreemap(&self) -> BTreeMap<K, T> {
+        self.cloned_pairs().collect()
+    }
+
+    /// Values as a plain `Vec` if the keys are exactly `0..len()`
+    ///
+    /// Distinct keys fill `0..len()` exactly when the maximum key is
+    /// `len() - 1`, so tries with gaps are rejected in O(log32 n).
+    pub fn as_dense_vec(&self) -> Option<Vec<T>> {
+        match self.max_key() {
+            Some(max) if max.to_index() != (self.length - 1) * key_step::<K>() => None,
+            _ => Some(self.iter().map(|(_, value)| value.clone()).collect()),
+        }
+    }
+
+    /// Successive batches of up to `chunk_size` entries in ascending key order
+    ///
+    /// Only the last batch may be shorter than `chunk_size`.
+    pub fn iter_chunks(&self, chunk_size: usize) -> impl Iterator<Item=Vec<(K, &T)>> {
+        assert!(chunk_size > 0, "Chunk size should be positive");
+
+        let mut iter = self.iter();
+
+        iter::from_fn(move || {
+            let chunk: Vec<_> = iter.by_ref().take(chunk_size).collect();
+
+            if chunk.is_empty() {
+                None
+            } else {
+                Some(chunk)
+            }
+        })
+    }
+
+    /// Entries with keys `< upto`
+    ///
+    /// The subtrees entirely below `upto` are shared with `self` and only
+    /// the nodes on the path of `upto` are rebuilt, so the dropped entries
+    /// are never built into a trie.
+    pub fn truncate(&self, upto: K) -> KeyedTrie<K, T> {
+        match (upto.to_index().checked_sub(1), self.root.as_ref()) {
+            (Some(end), Some(node)) => KeyedTrie::from_root(node.keep_until(0, end)),
+            _ => KeyedTrie::new(),
+        }
+    }
+
+    /// Entries with keys `< bound`, same as `truncate`
+    pub fn keys_below(&self, bound: K) -> KeyedTrie<K, T> {
+        self.truncate(bound)
+    }
+
+    /// Entries with keys `> bound`
+    ///
+    /// The mirror of `keys_below`, sharing the subtrees entirely above
+    /// `bound` and rebuilding only the nodes on its path.
+    pub fn keys_above(&self, bound: K) -> KeyedTrie<K, T> {
+        match (bound.to_index().checked_add(1), self.root.as_ref()) {
+            (Some(start), Some(node)) => KeyedTrie::from_root(node.keep_from(0, start)),
+            _ => KeyedTrie::new(),
+        }
+    }
+
+    /// Leading entries in ascending key order until `f` first returns `false`
+    ///
+    /// The traversal stops at the first rejected entry, and the trie is
+    /// cut there like `truncate`, sharing the subtrees before it.
+    pub fn take_while<F: FnMut(K, &T) -> bool>(&self, mut f: F) -> KeyedTrie<K, T> {
+        let mut taken = 0;
+        let cutoff = self.find_map(|key, value| {
+            if f(key, value) {
+                taken += 1;
+                None
+            } else {
+                Some(key.to_index())
+            }
+        });
+
+        let cutoff = match cutoff {
+            None => return self.clone(),
+            Some(cutoff) => cutoff,
+        };
+
+        match (cutoff.checked_sub(1), self.root.as_ref()) {
+            (Some(end), Some(node)) => KeyedTrie {
+                root: node.keep_until(0, end),
+                length: taken,
+                key: PhantomData,
+            },
+            _ => KeyedTrie::new(),
+        }
+    }
+
+    /// Entries in ascending key order from the first one for which `f` returns `false`
+    ///
+    /// The leading entries accepted by `f` are dropped, and the subtrees
+    /// after the first rejected entry are shared with `self`.
+    pub fn skip_while<F: FnMut(K, &T) -> bool>(&self, mut f: F) -> KeyedTrie<K, T> {
+        let mut skipped = 0;
+        let cutoff = self.find_map(|key, value| {
+            if f(key, value) {
+                skipped += 1;
+                None
+            } else {
+                Some(key.to_index())
+            }
+        });
+
+        match (cutoff, self.root.as_ref()) {
+            (Some(start), Some(node)) => KeyedTrie {
+                root: node.keep_from(0, start),
+                length: self.length - skipped,
+                key: PhantomData,
+            },
+            _ => KeyedTrie::new(),
+        }
+    }
+
+    /// Entries of the `n` smallest keys
+    ///
+    /// The boundary key is found in O(log32 n) by descending through the
+    /// cached lengths of the inner nodes, and the subtrees entirely below
+    /// it are shared with `self`.
+    pub fn take_first_n(&self, n: usize) -> KeyedTrie<K, T> {
+        if n >= self.length {
+            return self.clone();
+        }
+
+        match (n.checked_sub(1), self.root.as_ref()) {
+            (Some(last), Some(node)) => KeyedTrie {
+                root: node.keep_until(0, node.nth_key(last)),
+                length: n,
+                key: PhantomData,
+            },
+            _ => KeyedTrie::new(),
+        }
+    }
+
+    /// Entries of the `n` largest keys
+    ///
+    /// The boundary key is found like in `take_first_n`, and the subtrees
+    /// entirely above it are shared with `self`.
+    pub fn take_last_n(&self, n: usize) -> KeyedTrie<K, T> {
+        if n >= self.length {
+            return self.clone();
+        }
+
+        match (n, self.root.as_ref()) {
+            (0, _) | (_, None) => KeyedTrie::new(),
+            (_, Some(node)) => KeyedTrie {
+                root: node.keep_from(0, node.nth_key(self.length - n)),
+                length: n,
+                key: PhantomData,
+            },
+        }
+    }
+
+    /// Independent copy of the entries within `range`
+    ///
+    /// Unlike `subtree`, which shares the matching node with `self`,
+    /// every node of the result is freshly built, so it shares
+    /// nothing with `self` and `self` is left untouched.
+    pub fn extract_range<R: RangeBounds<K>>(&self, range: R) -> KeyedTrie<K, T> {
+        let entries: Vec<_> = self.range(range)
+            .map(|(key, value)| (key, value.clone()))
+            .collect();
+
+        KeyedTrie::from_sorted_slice(&entries)
+    }
+
+    /// Clone of `self` sharing every node, and an independent copy of the entries in `start..end`
+    ///
+    /// The copy is built like `extract_range`, so editing it never touches
+    /// `self` or the clone. Once processed, it can be written back over
+    /// the region with `splice(start..end, ...)`.
+    pub fn fork_region(&self, start: K, end: K) -> (KeyedTrie<K, T>, KeyedTrie<K, T>) {
+        (self.clone(), self.extract_range(start..end))
+    }
+
+    /// Keys within `range`, in ascending order
+    pub fn keys_in_range<R: RangeBounds<K>>(&self, range: R) -> impl Iterator<Item=K> + '_ {
+        self.range(range).map(|entry| entry.0)
+    }
+
+    /// Values whose keys are within `range`, in ascending key order
+    pub fn values_in_range<R: RangeBounds<K>>(&self, range: R) -> impl Iterator<Item=&T> + '_ {
+        self.range(range).map(|entry| entry.1)
+    }
+
+    /// Maximal runs of empty keys within `range`, in ascending order
+    ///
+    /// The runs are the holes between the occupied keys of the range.
+    /// Since a `Range<K>` can't end past `K::MAX`,
+    /// the key `K::MAX` itself is never reported as empty.
+    pub fn gaps<R: RangeBounds<K>>(&self, range: R) -> impl Iterator<Item=ops::Range<K>> + '_ {
+        let step = key_step::<K>();
+        let max = !(step - 1);
+        let start = match range.start_bound() {
+            Bound::Included(&start) => Some(start.to_index()),
+            Bound::Excluded(&start) => start.to_index().checked_add(step),
+            Bound::Unbounded => Some(0),
+        };
+        let end = match range.end_bound() {
+            Bound::Included(&end) => end.to_index().saturating_add(step).min(max),
+            Bound::Excluded(&end) => end.to_index(),
+            Bound::Unbounded => max,
+        };
+
+        let mut next = start.filter(|&start| start < end);
+        let mut keys = self.range(K::from_index(next.unwrap_or(0))..K::from_index(end));
+
+        iter::from_fn(move || loop {
+            let from = next?;
+
+            match keys.next() {
+                Some((key, _)) => {
+                    next = Some(key.to_index() + step);
+
+                    if key.to_index() > from {
+                        return Some(K::from_index(from)..key);
+                    }
+                }
+                None => {
+                    next = None;
+
+                    if from < end {
+                        return Some(K::from_index(from)..K::from_index(end));
+                    }
+                }
+            }
+        })
+    }
+
+    /// Least key `>= start` followed by `length` empty keys including itself
+    ///
+    /// Occupied regions are skipped a gap at a time through `gaps`,
+    /// so the key `K::MAX` is never part of a run.
+    pub fn first_free_run(&self, start: K, length: usize) -> Option<K> {
+        if length == 0 {
+            return Some(start);
+        }
+
+        let step = key_step::<K>();
+
+        self.gaps(start..)
+            .find(|gap| (gap.end.to_index() - gap.start.to_index()) / step >= length)
+            .map(|gap| gap.start)
+    }
+
+    /// Maximal runs of consecutive occupied keys, in ascending order
+    ///
+    /// This is the complement of `gaps` over the whole key space. The
+    /// ranges are inclusive, so a run ending at `K::MAX` is reported
+    /// as well.
+    pub fn occupied_ranges(&self) -> Vec<ops::RangeInclusive<K>> {
+        let step = key_step::<K>();
+        let mut ranges: Vec<ops::RangeInclusive<K>> = Vec::new();
+
+        self.for_each(|key, _| {
+            if let Some(last) = ranges.last_mut() {
+                if last.end().to_index() + step == key.to_index() {
+                    *last = *last.start()..=key;
+                    return;
+                }
+            }
+
+            ranges.push(key..=key);
+        });
+
+        ranges
+    }
+
+    /// Maximal key ranges whose entries all pass `f`, in ascending order
+    ///
+    /// Only occupied keys are considered, so a range only ends at an entry
+    /// which fails `f` and the empty keys between two passing entries
+    /// belong to their range. Each range ends right after its last entry.
+    /// Since a `Range<K>` can't end past `K::MAX` like in `gaps`, a range
+    /// whose last entry is at `K::MAX` ends there and leaves it out.
+    pub fn segments<F: FnMut(K, &T) -> bool>(&self, mut f: F) -> Vec<ops::Range<K>> {
+        let step = key_step::<K>();
+        let mut segments = Vec::new();
+        let mut current: Option<ops::Range<K>> = None;
+
+        self.for_each(|key, value| {
+            if f(key, value) {
+                let end = K::from_index(key.to_index().saturating_add(step));
+
+                match current {
+                    Some(ref mut segment) => segment.end = end,
+                    None => current = Some(key..end),
+                }
+            } else if let Some(segment) = current.take() {
+                segments.push(segment);
+            }
+        });
+
+        segments.extend(current);
+        segments
+    }
+
+    /// Maximal runs of consecutive keys with equal values, as `(start, length, value)`
+    ///
+    /// The runs are in ascending order, and `from_run_length` rebuilds
+    /// the same trie from them. This is compact for dense tries with long
+    /// stretches of repeated values, but sparse keys cost a run each.
+    pub fn run_length_encode(&self) -> Vec<(K, usize, T)> where T: PartialEq {
+        let step = key_step::<K>();
+        let mut runs: Vec<(K, usize, T)> = Vec::new();
+
+        self.for_each(|key, value| {
+            if let Some(&mut (start, ref mut length, ref run_value)) = runs.last_mut() {
+                if start.to_index() + *length * step == key.to_index() && run_value == value {
+                    *length += 1;
+                    return;
+                }
+            }
+
+            runs.push((key, 1, value.clone()));
+        });
+
+        runs
+    }
+
+    /// Entries in descending key order, same as `iter().rev()`
+    pub fn iter_rev(&self) -> impl Iterator<Item=(K, &T)> {
+        self.iter().rev()
+    }
+
+    /// Insert every entry, also returning the `(key, old_value)` pairs they overwrote
+    ///
+    /// The displaced values are reported in the order of `iter`, including
+    /// those overwritten by earlier entries of `iter` itself.
+    pub fn update_all_returning<I: IntoIterator<Item=(K, T)>>(&self, iter: I) -> (KeyedTrie<K, T>, Vec<(K, T)>) {
+        let mut iter = iter.into_iter().peekable();
+
+        if iter.peek().is_none() {
+            return (self.clone(), Vec::new());
+        }
+
+        let mut trie_mut = self.to_mut();
+        let mut displaced = Vec::new();
+
+        for (key, value) in iter {
+            if let Some(old) = trie_mut.insert(key, value) {
+                displaced.push((key, old));
+            }
+        }
+
+        (trie_mut.into_trie(), displaced)
+    }
+
+    /// Insert every entry, combining it with the value already stored at its key
+    ///
+    /// `combine(existing, new)` decides the stored value whenever the key is
+    /// present in `self` or occurred earlier in `iter`, which makes this
+    /// the accumulation step of reducers.
+    pub fn update_all_with<I, F>(&self, iter: I, combine: F) -> KeyedTrie<K, T>
+    where
+        I: IntoIterator<Item=(K, T)>,
+        F: Fn(&T, T) -> T,
+    {
+        let mut iter = iter.into_iter().peekable();
+
+        if iter.peek().is_none() {
+            return self.clone();
+        }
+
+        let mut trie_mut = self.to_mut();
+
+        for (key, value) in iter {
+            trie_mut.update_path(key, |existing| match existing {
+                Some(existing) => Some(combine(&existing, value)),
+                None => Some(value),
+            });
+        }
+
+        trie_mut.into_trie()
+    }
+
+    /// Update only if `pred` accepts the current value at `key`
+    ///
+    /// Returns `None` without building anything when `pred` rejects it,
+    /// which allows compare-and-swap style updates of a snapshot.
+    pub fn update_if<F: FnOnce(Option<&T>) -> bool>(&self, key: K, value: T, pred: F) -> Option<KeyedTrie<K, T>> {
+        if pred(self.get_ref(key)) {
+            Some(self.update(key, value))
+        } else {
+            None
+        }
+    }
+
+    /// Update `key`, reporting whether the key was inserted, replaced or left as is
+    ///
+    /// Writing a value equal to the existing one returns a clone of `self`
+    /// sharing its root, so idempotent update loops don't rebuild paths.
+    /// A different value only copies the path like `replace`.
+    pub fn update_checked(&self, key: K, value: T) -> (KeyedTrie<K, T>, UpdateKind) where T: PartialEq {
+        match self.get_ref(key) {
+            None => (self.update(key, value), UpdateKind::Inserted),
+            Some(existing) if *existing == value => (self.clone(), UpdateKind::Unchanged),
+            Some(_) => {
+                let trie = self.replace(key, value).expect("Present key should be replaced");
+                (trie, UpdateKind::Replaced)
+            }
+        }
+    }
+
+    /// Insert only if `key` is absent, reporting whether it was inserted
+    ///
+    /// An existing value is never overwritten, and the returned trie
+    /// shares everything with `self` in that case.
+    pub fn insert_if_absent(&self, key: K, value: T) -> (KeyedTrie<K, T>, bool) {
+        if self.contains_key(key) {
+            return (self.clone(), false);
+        }
+
+        (self.update(key, value), true)
+    }
+
+    /// Overwrite the value at `key` only if the key is present
+    ///
+    /// The key set stays the same, so only the nodes on the path to
+    /// `key` are copied and every other subtree is shared with `self`.
+    pub fn replace(&self, key: K, value: T) -> Option<KeyedTrie<K, T>> {
+        let root = self.root.as_ref()?.replace(0, key.to_index(), value)?;
+
+        Some(KeyedTrie {
+            root: Some(root),
+            length: self.length,
+            key: PhantomData,
+        })
+    }
+
+    /// Remove every key, also returning the `(key, value)` pairs actually removed
+    ///
+    /// The pairs are in the order of `iter`, and absent keys are skipped.
+    pub fn remove_all_returning<I: IntoIterator<Item=K>>(&self, iter: I) -> (KeyedTrie<K, T>, Vec<(K, T)>) {
+        let mut iter = iter.into_iter().peekable();
+
+        if iter.peek().is_none() {
+            return (self.clone(), Vec::new());
+        }
+
+        let mut trie_mut = self.to_mut();
+        let mut removed = Vec::new();
+
+        for key in iter {
+            if let Some(value) = trie_mut.remove(key) {
+                removed.push((key, value));
+            }
+        }
+
+        (trie_mut.into_trie(), removed)
+    }
+
+    /// Apply interleaved insertions and removals in order, committing once
+    ///
+    /// When several operations touch the same key, the last one wins.
+    pub fn batch<I: IntoIterator<Item=Op<K, T>>>(&self, ops: I) -> Self {
+        let mut trie_mut = self.to_mut();
+
+        for op in ops {
+            match op {
+                Op::Insert(key, value) => {
+                    trie_mut.insert(key, value);
+                }
+                Op::Remove(key) => {
+                    trie_mut.remove(key);
+                }
+            }
+        }
+
+        trie_mut.into_trie()
+    }
+
+    /// Store clones of `value` at the `count` keys from `start`
+    ///
+    /// The filled keys are contiguous, so their nodes are built bottom-up
+    /// in one pass and then merged into `self` level by level, instead
+    /// of being inserted one by one.
+    pub fn fill_range(&self, start: K, count: usize, value: T) -> KeyedTrie<K, T> {
+        let step = key_step::<K>();
+        let last = match count.checked_sub(1) {
+            None => return self.clone(),
+            Some(offset) => offset.checked_mul(step)
+                .and_then(|offset| start.to_index().checked_add(offset))
+                .unwrap_or_else(|| panic!("Filling {} keys from {} overflows the key type", count, start)),
+        };
+
+        let entries: Vec<_> = (start.to_index()..=last).step_by(step)
+            .map(|index| (index, value.clone()))
+            .collect();
+        let filled = build_sorted(0, &entries).unwrap();
+
+        match self.root {
+            None => KeyedTrie {
+                root: Some(filled),
+                length: count,
+                key: PhantomData,
+            },
+            Some(ref node) => KeyedTrie {
+                root: Some(node.overwrite(0, &filled)),
+                length: self.length + count - self.keys_in_range(start..=K::from_index(last)).count(),
+                key: PhantomData,
+            },
+        }
+    }
+
+    /// Make every key in `start..end` present, filling the missing ones with `T::default()`
+    ///
+    /// Existing values are kept. The defaults for the whole range are built
+    /// bottom-up like in `fill_range`, and the existing entries are merged
+    /// over them.
+    pub fn densify_range(&self, start: K, end: K) -> KeyedTrie<K, T> where T: Default {
+        if start >= end {
+            return self.clone();
+        }
+
+        let entries: Vec<_> = (start.to_index()..end.to_index()).step_by(key_step::<K>())
+            .map(|index| (index, T::default()))
+            .collect();
+        let filled = build_sorted(0, &entries).unwrap();
+
+        match self.root {
+            None => KeyedTrie {
+                root: Some(filled),
+                length: entries.len(),
+                key: PhantomData,
+            },
+            Some(ref node) => KeyedTrie {
+                root: Some(filled.overwrite(0, node)),
+                length: self.length + entries.len() - self.keys_in_range(start..end).count(),
+                key: PhantomData,
+            },
+        }
+    }
+
+    /// Replace every entry within `range` with the `replacement` entries
+    ///
+    /// Keys of `replacement` outside of `range` are inserted as well,
+    /// overwriting the existing values there like `update_all` does.
+    pub fn splice<R, I>(&self, range: R, replacement: I) -> KeyedTrie<K, T>
+    where
+        R: RangeBounds<K>,
+        I: IntoIterator<Item=(K, T)>,
+    {
+        let mut trie_mut = self.to_mut();
+
+        for key in self.keys_in_range(range) {
+            trie_mut.remove(key);
+        }
+
+        trie_mut.extend(replacement);
+        trie_mut.into_trie()
+    }
+
+    /// Remove keys given in ascending order, in a single pass over the trie
+    ///
+    /// The trie is walked in lock-step with `keys`, so each node is
+    /// visited at most once and the subtrees without any removed key
+    /// stay shared with `self`. Unsorted keys are only detected in
+    /// debug builds, and duplicated keys are allowed.
+    pub fn remove_sorted<I: IntoIterator<Item=K>>(&self, keys: I) -> KeyedTrie<K, T> {
+        let mut keys = assert_sorted(keys.into_iter().map(K::to_index)).peekable();
+        let mut removed = 0;
+
+        match self.root.as_ref().and_then(|node| node.remove_sorted(0, 0, &mut keys, &mut removed)) {
+            None => self.clone(),
+            Some(root) => KeyedTrie {
+                root,
+                length: self.length - removed,
+                key: PhantomData,
+            },
+        }
+    }
+
+    /// Remove `key`, returning the next occupied key after it as well
+    ///
+    /// This lets a cursor keep deleting in ascending order
+    /// without searching for its next position separately.
+    pub fn remove_and_next(&self, key: K) -> (KeyedTrie<K, T>, Option<K>) {
+        let next = self.range((Bound::Excluded(key), Bound::Unbounded))
+            .next()
+            .map(|entry| entry.0);
+
+        (self.remove(key), next)
+    }
+
+    pub fn next_empty(&self, start: K) -> Option<K> {
+        let step = key_step::<K>();
+
+        self.root.as_ref()
+            .map_or(Some(start.to_index()), |node| {
+                node.next_empty(0, start.to_index(), step)
+                    .or_else(|| node.next_empty(0, 0, step))
+            })
+            .map(K::from_index)
+    }
+
+    /// Entries of the requested keys which are present in `self`
+    pub fn select_keys<I: IntoIterator<Item=K>>(&self, keys: I) -> KeyedTrie<K, T> {
+        let mut trie_mut = KeyedTrie::default().to_mut();
+
+        for key in keys {
+            if let Some(value) = self.get_ref(key) {
+                trie_mut.insert(key, value.clone());
+            }
+        }
+
+        trie_mut.into_trie()
+    }
+
+    /// Entry with the greatest key `<= key`
+    pub fn floor(&self, key: K) -> Option<(K, &T)> {
+        self.root.as_ref()
+            .and_then(|node| node.floor(0, key.to_index()))
+            .map(|(index, value)| (K::from_index(index), value))
+    }
+
+    /// Entry with the least key `>= key`
+    pub fn ceil(&self, key: K) -> Option<(K, &T)> {
+        self.root.as_ref()
+            .and_then(|node| node.ceil(0, key.to_index()))
+            .map(|(index, value)| (K::from_index(index), value))
+    }
+
+    /// Number of significant bits in the maximum key, or 0 if empty
+    pub fn max_key_bits(&self) -> u32 {
+        let bits = K::BITS as u32;
+
+        self.max_key().map_or(0, |max| bits - max.to_index().leading_zeros().min(bits))
+    }
+
+    /// Minimum and maximum keys, sharing the descent until the paths fork
+    pub fn key_range(&self) -> Option<(K, K)> {
+        self.root.as_ref().map(|node| {
+            let (min, max) = node.key_range();
+            (K::from_index(min), K::from_index(max))
+        })
+    }
+
+    /// Entries whose keys share the high `prefix_bits` bits of `prefix`
+    ///
+    /// `prefix_bits` must be a multiple of 5 so the prefix ends on a node
+    /// boundary, and the remaining low bits of `prefix` are ignored.
+    /// The result keeps the original keys and shares the matching node
+    /// with `self`, so it only takes O(log32 n) to build.
+    pub fn subtree(&self, prefix: K, prefix_bits: usize) -> KeyedTrie<K, T> {
+        assert!(prefix_bits.is_multiple_of(5) && prefix_bits <= K::BITS,
+            "Prefix length must be a multiple of 5 within the key, but received {}", prefix_bits);
+
+        KeyedTrie::from_root(self.root.as_ref()
+            .and_then(|node| node.subtree(0, prefix.to_index(), prefix_bits / 5)))
+    }
+
+    /// Number of keys which share the high `prefix_bits` bits of `prefix`
+    ///
+    /// `prefix_bits` must be a multiple of 5 like in `subtree`. Inner nodes
+    /// cache their lengths, so this takes O(log32 n) to find the matching node.
+    pub fn prefix_count(&self, prefix: K, prefix_bits: usize) -> usize {
+        assert!(prefix_bits.is_multiple_of(5) && prefix_bits <= K::BITS,
+            "Prefix length must be a multiple of 5 within the key, but received {}", prefix_bits);
+
+        self.root.as_ref()
+            .and_then(|node| node.find_prefix(0, prefix.to_index(), prefix_bits / 5))
+            .map_or(0, |node| node.len())
+    }
+
+    /// Chunks of `key` followed through the inner nodes toward it
+    ///
+    /// The descent stops at a leaf or at an inner node without the child,
+    /// whether or not `key` is present. The chunk at each depth is
+    /// `Index32::convert(key.to_index(), depth)`, the same routing `get`
+    /// and `update` follow.
+    pub fn path_keys(&self, key: K) -> Vec<Index32> {
+        let index = key.to_index();
+        let mut path = Vec::new();
+        let mut node = self.root.as_ref();
+
+        while let Some(&More { bitset, ref nodes, .. }) = node {
+            let idx32 = Index32::convert(index, path.len());
+            path.push(idx32);
+            node = bitset.packed_index(idx32).map(|idx| &nodes[idx]);
+        }
+
+        path
+    }
+
+    /// Children of the inner node reached by following `prefix` down to `depth`
+    ///
+    /// Returns `None` if the path ends at a leaf or a missing child before
+    /// reaching `depth`. This exposes the branching for diagnostics
+    /// without exposing the nodes themselves.
+    pub fn level_bitset(&self, prefix: K, depth: usize) -> Option<Bitset> {
+        match *self.root.as_ref()?.find_prefix(0, prefix.to_index(), depth)? {
+            More { bitset, .. } => Some(bitset),
+            One { .. } => None,
+        }
+    }
+
+    /// Subtrees below each child of the root as `(prefix, prefix_bits, subtree)`
+    ///
+    /// Every subtree is what `subtree(prefix, prefix_bits)` returns for the
+    /// 5 bit prefix of a child, and together they hold every entry of
+    /// `self` exactly once. They are yielded by value rather than as
+    /// `&KeyedTrie`, since keys are routed from the root at depth 0 and
+    /// a child can't serve as the root of a trie by itself. Instead each
+    /// inner child gets a new root holding only that child, which is the
+    /// only allocation per subtree, while every node below it is shared.
+    pub fn iter_subtrees(&self) -> impl Iterator<Item=(K, usize, KeyedTrie<K, T>)> + '_ {
+        let bitset = match self.root {
+            None => Bitset::new(),
+            Some(One { index, .. }) => Bitset::new().with_set(Index32::convert(index, 0)),
+            Some(More { bitset, .. }) => bitset,
+        };
+
+        bitset.iter().map(move |idx32| {
+            let prefix = K::from_index(idx32.num() << Index32::shift(0));
+            (prefix, 5, self.subtree(prefix, 5))
+        })
+    }
+
+    /// Entries of `self` whose keys are also present in `keys`
+    ///
+    /// Both tries are descended together and only the children present
+    /// in both bitsets are visited, so the values of `keys` are ignored.
+    pub fn restrict_to<U: Clone>(&self, keys: &KeyedTrie<K, U>) -> KeyedTrie<K, T> {
+        match (self.root.as_ref(), keys.root.as_ref()) {
+            (Some(node), Some(keys)) => KeyedTrie::from_root(node.restrict_to(0, keys)),
+            _ => KeyedTrie::new(),
+        }
+    }
+
+    /// Rebuild with every group of equal values cloned from one representative
+    ///
+    /// Reference counted values like `Arc<U>` end up sharing a single
+    /// allocation per distinct value. The structure of the trie is kept.
+    pub fn dedup_values(&self) -> KeyedTrie<K, T> where T: Eq + Hash {
+        let mut interned = HashSet::new();
+
+        KeyedTrie {
+            root: self.root.as_ref().map(|node| node.dedup_values(&mut interned)),
+            length: self.length,
+            key: PhantomData,
+        }
+    }
+
+    /// Transform every value, stopping at the first error
+    ///
+    /// Values are visited in ascending key order, and `f` receives the key
+    /// so the error can report it. On success the structure is kept as is.
+    pub fn try_map<U, E, F>(&self, mut f: F) -> Result<KeyedTrie<K, U>, E>
+    where
+        F: FnMut(K, &T) -> Result<U, E>,
+    {
+        let mut by_index = |index, value: &T| f(K::from_index(index), value);
+        let root = match self.root {
+            None => None,
+            Some(ref node) => Some(node.try_map(&mut by_index)?),
+        };
+
+        Ok(KeyedTrie {
+            root,
+            length: self.length,
+            key: PhantomData,
+        })
+    }
+
+    /// Running results of `f` threading `state` through the entries in ascending key order
+    ///
+    /// Each output is stored at the key of its entry, so e.g. prefix sums
+    /// over the ordered keys keep the structure of `self`.
+    pub fn scan<B, F>(&self, mut state: B, mut f: F) -> KeyedTrie<K, B>
+    where
+        B: Clone,
+        F: FnMut(&mut B, K, &T) -> B,
+    {
+        match self.try_map(|key, value| Ok::<B, Infallible>(f(&mut state, key, value))) {
+            Ok(trie) => trie,
+            Err(never) => match never {},
+        }
+    }
+
+    /// Number of entries for which `f` returns `true`
+    pub fn count_where<F: FnMut(K, &T) -> bool>(&self, mut f: F) -> usize {
+        let mut count = 0;
+
+        self.for_each(|key, value| {
+            if f(key, value) {
+                count += 1;
+            }
+        });
+
+        count
+    }
+
+    /// Number of entries in each group of the keys returned by `key_fn`
+    pub fn group_count<G, F>(&self, key_fn: F) -> HashMap<G, usize>
+    where
+        G: Eq + Hash,
+        F: Fn(K, &T) -> G,
+    {
+        let mut counts = HashMap::new();
+
+        self.for_each(|key, value| {
+            *counts.entry(key_fn(key, value)).or_insert(0) += 1;
+        });
+
+        counts
+    }
+
+    /// Whether `f` returns `true` for any entry, stopping at the first one
+    pub fn any<F: FnMut(K, &T) -> bool>(&self, mut f: F) -> bool {
+        self.try_for_each(|key, value| {
+            if f(key, value) {
+                ControlFlow::Break(())
+            } else {
+                ControlFlow::Continue(())
+            }
+        }).is_break()
+    }
+
+    /// Whether `f` returns `true` for every entry, stopping at the first failure
+    pub fn all<F: FnMut(K, &T) -> bool>(&self, mut f: F) -> bool {
+        self.try_for_each(|key, value| {
+            if f(key, value) {
+                ControlFlow::Continue(())
+            } else {
+                ControlFlow::Break(())
+            }
+        }).is_continue()
+    }
+
+    /// First `Some` returned by `f` in ascending key order, stopping there
+    pub fn find_map<U, F: FnMut(K, &T) -> Option<U>>(&self, mut f: F) -> Option<U> {
+        match self.try_for_each(|key, value| match f(key, value) {
+            Some(found) => ControlFlow::Break(found),
+            None => ControlFlow::Continue(()),
+        }) {
+            ControlFlow::Break(found) => Some(found),
+            ControlFlow::Continue(()) => None,
+        }
+    }
+
+    /// Number of keys mapped to each distinct value
+    pub fn value_counts(&self) -> HashMap<T, usize> where T: Eq + Hash {
+        self.group_count(|_, value| value.clone())
+    }
+
+    /// Fold every entry in ascending key order, stopping at the first error
+    pub fn try_fold<B, E, F>(&self, init: B, mut f: F) -> Result<B, E>
+    where
+        F: FnMut(B, K, &T) -> Result<B, E>,
+    {
+        let mut acc = Some(init);
+
+        let flow = self.try_for_each(|key, value| {
+            match f(acc.take().unwrap(), key, value) {
+                Ok(next) => {
+                    acc = Some(next);
+                    ControlFlow::Continue(())
+                }
+                Err(err) => ControlFlow::Break(err),
+            }
+        });
+
+        match flow {
+            ControlFlow::Continue(()) => Ok(acc.unwrap()),
+            ControlFlow::Break(err) => Err(err),
+        }
+    }
+
+    /// Visit every entry in ascending key order
+    fn for_each<F: FnMut(K, &T)>(&self, mut f: F) {
+        let flow = self.try_for_each(|key, value| {
+            f(key, value);
+            ControlFlow::Continue::<Infallible>(())
+        });
+
+        match flow {
+            ControlFlow::Continue(()) => {}
+            ControlFlow::Break(never) => match never {},
+        }
+    }
+
+    /// Visit entries in ascending key order until `f` breaks
+    fn try_for_each<B, F>(&self, mut f: F) -> ControlFlow<B>
+    where
+        F: FnMut(K, &T) -> ControlFlow<B>,
+    {
+        match self.root {
+            None => ControlFlow::Continue(()),
+            Some(ref node) => node.try_for_each(&mut |index, value| f(K::from_index(index), value)),
+        }
+    }
+
+    /// Fold every value in ascending key order with an associative `f`
+    pub fn reduce_values<F: Fn(&T, &T) -> T>(&self, f: F) -> Option<T> {
+        self.root.as_ref().map(|node| node.reduce_values(&f))
+    }
+
+    /// Map every entry with `map` and combine the results with an associative `reduce`
+    ///
+    /// The results are combined per subtree in ascending key order, so the
+    /// reduction follows the shape of the trie and never collects them.
+    pub fn map_reduce<B, F, G>(&self, mut map: F, mut reduce: G) -> Option<B>
+    where
+        F: FnMut(K, &T) -> B,
+        G: FnMut(B, B) -> B,
+    {
+        let mut by_index = |index, value: &T| map(K::from_index(index), value);
+
+        self.root.as_ref().map(|node| node.map_reduce(&mut by_index, &mut reduce))
+    }
+
+    /// Whether both tries have the same keys with values equal by `eq`
+    ///
+    /// The values may have different types, and the comparison stops
+    /// at the first mismatched key or value.
+    pub fn eq_by<U: Clone, F: Fn(&T, &U) -> bool>(&self, other: &KeyedTrie<K, U>, eq: F) -> bool {
+        self.len() == other.len() && self.iter().zip(other.iter())
+            .all(|((key, value), (other_key, other_value))| {
+                key == other_key && eq(value, other_value)
+            })
+    }
+
+    /// Whether both tries have the same entries within `range`
+    ///
+    /// Both tries are descended together through the children within the
+    /// range, and subtrees shared between them are skipped without traversal.
+    pub fn range_eq<R: RangeBounds<K>>(&self, other: &KeyedTrie<K, T>, range: R) -> bool where T: PartialEq {
+        let indices = (
+            range.start_bound().map(|key| key.to_index()),
+            range.end_bound().map(|key| key.to_index()),
+        );
+        let (start, end) = match bounds(&indices) {
+            Some(bounds) => bounds,
+            None => return true,
+        };
+
+        match (self.root.as_ref(), other.root.as_ref()) {
+            (None, None) => true,
+            (Some(node), None) | (None, Some(node)) => node.range(start, end).next().is_none(),
+            (Some(node), Some(other)) => node.range_eq(other, 0, 0, start, end),
+        }
+    }
+
+    /// Least key which is present in only one of the tries or has different values in each
+    ///
+    /// Subtrees sharing an allocation are skipped, and children are visited
+    /// in ascending order, so only the paths toward the first difference
+    /// are compared.
+    pub fn first_difference(&self, other: &KeyedTrie<K, T>) -> Option<K> where T: PartialEq {
+        let index = match (self.root.as_ref(), other.root.as_ref()) {
+            (None, None) => None,
+            (Some(node), None) | (None, Some(node)) => Some(node.min_key()),
+            (Some(node), Some(other)) => node.first_difference(other, 0),
+        };
+
+        index.map(K::from_index)
+    }
+
+    /// Changes turning `self` into `other`, yielded lazily in ascending key order
+    ///
+    /// Subtrees sharing an allocation are skipped without visiting their
+    /// entries, and values are borrowed from both tries, so large diffs
+    /// can be processed without collecting them.
+    pub fn diff_iter<'a>(&'a self, other: &'a KeyedTrie<K, T>) -> Diff<'a, K, T> where T: PartialEq {
+        let step = match (self.root.as_ref(), other.root.as_ref()) {
+            (None, None) => None,
+            (Some(node), None) => Some(DiffStep::Removed(node.entries().with_key())),
+            (None, Some(other)) => Some(DiffStep::Added(other.entries().with_key())),
+            (Some(node), Some(other)) => Some(DiffStep::Nodes(node, other)),
+        };
+
+        Diff {
+            pending: step.into_iter().collect(),
+        }
+    }
+
+    /// Whether both tries point to the same root allocation
+    ///
+    /// This is a cheap check that `other` is a clone of `self` which was
+    /// not modified since, without requiring `T: PartialEq`. Empty tries
+    /// always share their root, but a trie with a single entry stores it
+    /// inline, so it never shares a root with another trie.
+    pub fn shares_root(&self, other: &KeyedTrie<K, T>) -> bool {
+        match (&self.root, &other.root) {
+            (&None, &None) => true,
+            (&Some(More { ref nodes, .. }), &Some(More { nodes: ref other_nodes, .. })) => {
+                Arc::ptr_eq(nodes, other_nodes)
+            }
+            _ => false,
+        }
+    }
+
+    /// Reconstruct a canonical trie by reinserting every entry
+    ///
+    /// This is a safety net for tries built through a buggy path,
+    /// and just clones `self` when it already passes `validate`.
+    pub fn rebuild(&self) -> KeyedTrie<K, T> {
+        if self.validate() {
+            self.clone()
+        } else {
+            self.iter().map(|(key, value)| (key, value.clone())).collect()
+        }
+    }
+
+    /// Equivalent trie with the smallest node footprint
+    ///
+    /// Child slices are always allocated at their exact size and a key set
+    /// has only one canonical shape, so a trie passing `validate` is
+    /// already compact and is returned sharing every node. Other tries
+    /// are rebuilt bottom-up from their sorted entries, like `rebuild`
+    /// but without inserting entries one by one.
+    pub fn compact(&self) -> KeyedTrie<K, T> {
+        if self.validate() {
+            return self.clone();
+        }
+
+        let mut entries: Vec<_> = self.cloned_pairs().collect();
+        entries.sort_by_key(|entry| entry.0);
+        entries.dedup_by_key(|entry| entry.0);
+
+        KeyedTrie::from_sorted_slice(&entries)
+    }
+
+    /// Entries whose chunk at each depth `d` is set in `masks[d]`
+    ///
+    /// Depths past the end of `masks` are not restricted. Inner nodes only
+    /// visit the children allowed by the mask of their depth, so the
+    /// filter is driven by bitset intersections.
+    pub fn mask_levels(&self, masks: &[Bitset]) -> KeyedTrie<K, T> {
+        KeyedTrie::from_root(self.root.as_ref().and_then(|node| node.mask_levels(0, masks)))
+    }
+
+    /// Entries whose keys are in `allowed`
+    pub fn retain_matching(&self, allowed: &HashSet<K>) -> KeyedTrie<K, T> {
+        self.retain(|key, _| allowed.contains(&key))
+    }
+
+    /// Entries whose keys are within any of `ranges`
+    ///
+    /// `ranges` must be sorted and disjoint, which is only checked in debug
+    /// builds. Subtrees lying entirely outside of them are pruned, and
+    /// those lying entirely within one range are shared with `self`.
+    pub fn retain_in_ranges(&self, ranges: &[ops::Range<K>]) -> KeyedTrie<K, T> {
+        debug_assert!(ranges.windows(2).all(|pair| pair[0].end <= pair[1].start),
+            "Ranges should be sorted and disjoint");
+
+        let ranges: Vec<_> = ranges.iter()
+            .map(|range| range.start.to_index()..range.end.to_index())
+            .collect();
+
+        KeyedTrie::from_root(self.root.as_ref().and_then(|node| node.retain_in_ranges(0, 0, &ranges)))
+    }
+
+    /// Entries whose values pass `f`, regardless of their keys
+    pub fn retain_values<F: FnMut(&T) -> bool>(&self, mut f: F) -> KeyedTrie<K, T> {
+        self.retain(|_, value| f(value))
+    }
+
+    /// Split into the entries for which `f` returns `true` and the rest
+    ///
+    /// Both sides are canonical tries, so the removed entries can be
+    /// queried further. Values are moved out of the nodes owned only
+    /// by `self`, so only the nodes shared with other tries get cloned.
+    pub fn split_retain<F: FnMut(K, &T) -> bool>(self, mut f: F) -> (KeyedTrie<K, T>, KeyedTrie<K, T>) {
+        let placeholder = Arc::from(Vec::new());
+        let mut by_index = |index, value: &T| f(K::from_index(index), value);
+
+        let (kept, removed) = match self.root {
+            None => (None, None),
+            Some(node) => node.retain_into(&mut by_index, &placeholder),
+        };
+
+        (KeyedTrie::from_root(kept), KeyedTrie::from_root(removed))
+    }
+
+    /// Compact Bloom filter over the keys, for fast negative membership tests
+    ///
+    /// `bits` is rounded up to a multiple of 64, and every key sets two
+    /// of those slots chosen by a fixed hash. Query it with
+    /// `bloom_may_contain`, which has no false negatives but reports
+    /// false positives more often as the filter fills up.
+    pub fn membership_bloom(&self, bits: usize) -> Vec<u64> {
+        assert!(bits > 0, "Bloom filter should have at least one bit");
+
+        let mut bloom = vec![0; bits.div_ceil(64)];
+
+        for (key, _) in self.iter() {
+            for slot in bloom_slots(key.to_index(), bloom.len() * 64).iter() {
+                bloom[slot / 64] |= 1 << (slot % 64);
+            }
+        }
+
+        bloom
+    }
+
+    /// Hash of the trie where every inner node hashes the hashes of its children
+    ///
+    /// Equal tries have equal roots. Hashes use `DefaultHasher::new()`, so
+    /// they are only stable within a single build of the standard library.
+    pub fn merkle_root(&self) -> u64 where T: Hash {
+        self.merkle_root_with(&mut MerkleCache::new())
+    }
+
+    /// `merkle_root` reusing the hashes of subtrees shared with previous calls
+    ///
+    /// After updating a single key, only the nodes on its path are rehashed.
+    pub fn merkle_root_with(&self, cache: &mut MerkleCache<T>) -> u64 where T: Hash {
+        match self.root {
+            None => DefaultHasher::new().finish(),
+            Some(ref node) => node.merkle_hash(cache),
+        }
+    }
+
+    /// Number of distinct node allocations reachable from the root
+    ///
+    /// Each inner node stores its children in one shared allocation,
+    /// which is counted once however many times it's reachable.
+    /// Comparing this with a freshly built trie shows how much of the
+    /// storage is shared.
+    pub fn unique_node_count(&self) -> usize {
+        let mut visited = HashSet::new();
+
+        if let Some(ref node) = self.root {
+            node.visit_allocations(&mut visited);
+        }
+
+        visited.len()
+    }
+
+    /// Number of leaves and of inner nodes, as `(leaves, inner_nodes)`
+    ///
+    /// Every entry is a leaf, so the first count equals `len()`. The second
+    /// shows the overhead of the chosen keys: keys sharing a long prefix
+    /// hang below a chain of single-child inner nodes, while distant keys
+    /// branch off near the root.
+    pub fn node_counts(&self) -> (usize, usize) {
+        let mut counts = (0, 0);
+
+        if let Some(ref node) = self.root {
+            node.count_nodes(&mut counts);
+        }
+
+        counts
+    }
+
+    /// Bytes of the distinct node allocations reachable from the root
+    ///
+    /// Like `unique_node_count`, shared allocations are counted once, each
+    /// with the reference counts of its `Arc`. Heap memory owned by the
+    /// values themselves is not included.
+    pub fn heap_size(&self) -> usize {
+        let mut visited = HashSet::new();
+
+        match self.root {
+            None => 0,
+            Some(ref node) => node.heap_size(&mut visited),
+        }
+    }
+}
+
+impl<K: TrieKey, J: TrieKey> KeyedTrie<K, J> {
+    /// Map every key through `self` and then through `other`
+    ///
+    /// Keys whose intermediate key is absent from `other` are dropped.
+    pub fn compose<T: Clone>(&self, other: &KeyedTrie<J, T>) -> KeyedTrie<K, T> {
+        let entries: Vec<_> = self.iter()
+            .filter_map(|(key, &mid)| other.get_ref(mid).map(|value| (key, value.clone())))
+            .collect();
+
+        KeyedTrie::from_sorted_slice(&entries)
+    }
+}
+
+impl<T> MerkleCache<T> {
+    pub fn new() -> Self {
+        MerkleCache {
+            hashes: HashMap::new(),
+        }
+    }
+
+    /// Number of inner nodes whose hashes are remembered
+    pub fn len(&self) -> usize {
+        self.hashes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hashes.is_empty()
+    }
+
+    /// Forget every hash, releasing the nodes kept alive by the cache
+    pub fn clear(&mut self) {
+        self.hashes.clear()
+    }
+}
+
+impl<T> Default for MerkleCache<T> {
+    fn default() -> Self {
+        MerkleCache::new()
+    }
+}
+
+impl<K: TrieKey> fmt::Display for AliasError<K> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            AliasError::Duplicate(key) => write!(f, "Index {} is requested more than once", key),
+            AliasError::Absent(key) => write!(f, "Index {} is not present", key),
+        }
+    }
+}
+
+impl<K: TrieKey> Error for AliasError<K> {}
+
+impl<K: TrieKey, T: Clone> Default for KeyedTrie<K, T> {
+    fn default() -> Self {
+        KeyedTrie::from_root(None)
+    }
+}
+
+impl<K: TrieKey, T: Clone> Clone for KeyedTrie<K, T> {
+    fn clone(&self) -> Self {
+        KeyedTrie {
+            root: self.root.clone(),
+            length: self.length,
+            key: PhantomData,
+        }
+    }
+}
+
+impl<K: TrieKey, T: PartialEq> PartialEq for KeyedTrie<K, T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.length == other.length && self.root == other.root
+    }
+}
+
+impl<K: TrieKey, T: Eq> Eq for KeyedTrie<K, T> {}
+
+impl<K: TrieKey, T: Clone> Extend<(K, T)> for KeyedTrie<K, T> {
+    fn extend<I: IntoIterator<Item=(K, T)>>(&mut self, iter: I) {
+        let mut trie_mut = self.to_mut();
+        trie_mut.extend(iter);
+        *self = trie_mut.into_trie();
+    }
+}
+
+impl<K: TrieKey, T: Clone> FromIterator<(K, T)> for KeyedTrie<K, T> {
+    fn from_iter<I: IntoIterator<Item=(K, T)>>(iter: I) -> Self {
+        let trie: KeyedTrieMut<K, T> = iter.into_iter().collect();
+        trie.into_trie()
+    }
+}
+
+impl<K: TrieKey, T: Clone> IntoIterator for KeyedTrie<K, T> {
+    type Item = (K, T);
+    type IntoIter = IntoIter<K, T>;
+
+    fn into_iter(self) -> IntoIter<K, T> {
+        self.into_iter_sorted()
+    }
+}
+
+impl<K: TrieKey, T: Clone> KeyedTrieMut<K, T> {
+    /// Empty session whose root is preallocated for `capacity` children
+    ///
+    /// Bulk inserts of keys spread over the whole index space populate
+    /// every child of the root, so this avoids regrowing the root while
+    /// they come in. The hint is clamped to the number of root children.
+    pub fn new_with_root_capacity(capacity: usize) -> Self {
+        let capacity = capacity.min(Index32::max_with(0).num() + 1);
+
+        KeyedTrieMut {
+            root: MoreMut(Vec::with_capacity(capacity)),
+            key: PhantomData,
+        }
+    }
+
+    /// Mutable references to the values at several distinct keys at once
+    ///
+    /// Absent keys get `None`. Panics if any key is repeated,
+    /// since the references would alias.
+    pub fn get_disjoint_mut<const N: usize>(&mut self, keys: [K; N]) -> [Option<&mut T>; N] {
+        for (pos, &key) in keys.iter().enumerate() {
+            assert!(!keys[..pos].contains(&key),
+                "Keys should be distinct, but {} is repeated", key);
+        }
+
+        let wanted: Vec<_> = keys.iter().map(|key| key.to_index()).zip(0..).collect();
+        let mut res = array::from_fn(|_| None);
+        self.root.get_disjoint_mut(0, &wanted, &mut res);
+
+        res
+    }
+
+    /// Mutable references to the values at `keys`, in the same order
+    ///
+    /// Unlike `get_disjoint_mut`, this reports repeated keys as an error
+    /// instead of panicking, so the keys may come from untrusted input.
+    /// Every key must be present as well, or the first absent one is
+    /// reported.
+    pub fn get_many_mut(&mut self, keys: &[K]) -> Result<Vec<&mut T>, AliasError<K>> {
+        let mut seen = HashSet::new();
+
+        if let Some(&key) = keys.iter().find(|&&key| !seen.insert(key)) {
+            return Err(AliasError::Duplicate(key));
+        }
+
+        let wanted: Vec<_> = keys.iter().map(|key| key.to_index()).zip(0..).collect();
+        let mut res: Vec<_> = keys.iter().map(|_| None).collect();
+        self.root.get_disjoint_mut(0, &wanted, &mut res);
+
+        res.into_iter()
+            .zip(keys)
+            .map(|(value, &key)| value.ok_or(AliasError::Absent(key)))
+            .collect()
+    }
+
+    /// Insert only if `key` is absent, returning whether it was inserted
+    pub fn insert_if_absent(&mut self, key: K, value: T) -> bool {
+        let mut inserted = false;
+
+        self.get_mut_or_insert_with(key, || {
+            inserted = true;
+            value
+        });
+
+        inserted
+    }
+
+    /// Copy the shared nodes on the path to `key` into owned ones up front
+    ///
+    /// This is a hint for clustered updates, so that later insertions
+    /// around `key` don't need to copy the same nodes again.
+    pub fn reserve_path(&mut self, key: K) {
+        self.root.reserve_path(0, key.to_index())
+    }
+
+    /// Mutable reference to the value at `key`, inserting `f()` if absent
+    ///
+    /// The path is descended only once, and `f` is not called when
+    /// the key already exists.
+    pub fn get_mut_or_insert_with<F: FnOnce() -> T>(&mut self, key: K, f: F) -> &mut T {
+        self.root.get_mut_or_insert_with(0, key.to_index(), f)
+    }
+
+    /// Replace the value at `key` by `f` of the current one, in a single descent
+    ///
+    /// `f` receives `None` if the key is absent,
+    /// and the key is removed if `f` returns `None`.
+    pub fn update_path<F: FnOnce(Option<T>) -> Option<T>>(&mut self, key: K, f: F) {
+        self.root.update_path(0, key.to_index(), f)
+    }
+
+    /// Combine `value` into the existing value at `key` in place, or insert it if absent
+    ///
+    /// This is the upsert used for accumulators like counters,
+    /// descending the path only once.
+    pub fn merge<F: FnOnce(&mut T, T)>(&mut self, key: K, value: T, combine: F) {
+        let mut value = Some(value);
+        let existing = self.get_mut_or_insert_with(key, || value.take().unwrap());
+
+        if let Some(value) = value {
+            combine(existing, value);
+        }
+    }
+
+    /// Keep the entries for which `f` returns `true`, returning the others
+    ///
+    /// `f` may modify the values in place. The removed entries are moved
+    /// out in ascending key order, so only the nodes shared with other
+    /// tries get cloned on the way.
+    pub fn retain_returning<F: FnMut(K, &mut T) -> bool>(&mut self, mut f: F) -> Vec<(K, T)> {
+        let mut removed = Vec::new();
+        self.root.retain_returning(&mut |index, value: &mut T| f(K::from_index(index), value), &mut removed);
+
+        removed.into_iter()
+            .map(|(index, value)| (K::from_index(index), value))
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.root.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        match self.root {
+            Empty => true,
+            MoreMut(ref pairs) => pairs.is_empty(),
+            Imut(_) => false,
+        }
+    }
+
+    pub fn insert(&mut self, key: K, value: T) -> Option<T> {
+        infallible(self.root.insert(0, key.to_index(), value))
+    }
+
+    /// Fallible variant of `insert`
+    ///
+    /// Returns an error instead of aborting when the nodes on the path
+    /// can't grow to hold the new entry, leaving the trie unchanged.
+    pub fn try_insert(&mut self, key: K, value: T) -> Result<Option<T>, TryReserveError> {
+        self.root.insert(0, key.to_index(), value)
+    }
+
+    pub fn remove(&mut self, key: K) -> Option<T> {
+        self.root.remove(0, key.to_index())
+    }
+
+    /// Snapshot the current state as a `Trie`, keeping the session usable
+    ///
+    /// The owned nodes are frozen into shared ones which both the snapshot
+    /// and `self` point to, so later edits copy only the nodes they touch
+    /// and never affect the snapshot.
+    pub fn commit(&mut self) -> KeyedTrie<K, T> {
+        let root = mem::replace(&mut self.root, Empty).into_node();
+
+        if let Some(ref node) = root {
+            self.root = Imut(node.clone());
+        }
+
+        KeyedTrie::from_root(root)
+    }
+
+    pub fn into_trie(self) -> KeyedTrie<K, T> {
+        KeyedTrie::from_root(self.root.into_node())
+    }
+}
+
+impl<K: TrieKey, T: Clone> Extend<(K, T)> for KeyedTrieMut<K, T> {
+    fn extend<I: IntoIterator<Item=(K, T)>>(&mut self, iter: I) {
+        for (key, value) in iter {
+            self.insert(key, value);
+        }
+    }
+}
+
+impl<K: TrieKey, T: Clone> FromIterator<(K, T)> for KeyedTrieMut<K, T> {
+    fn from_iter<I: IntoIterator<Item=(K, T)>>(iter: I) -> Self {
+        let mut trie_mut = KeyedTrie::default().to_mut();
+        trie_mut.extend(iter);
+        trie_mut
+    }
+}
+
+impl<'a, K: TrieKey, T> Iterator for Iter<'a, K, T> {
+    type Item = (K, &'a T);
+
+    fn next(&mut self) -> Option<(K, &'a T)> {
+        if self.remaining == 0 {
+            return None;
+        }
+
+        loop {
+            let node = match self.stack.last_mut()?.next() {
+                Some(node) => node,
+                None => {
+                    self.stack.pop();
+                    continue;
+                }
+            };
+
+            match *node {
+                One { index, ref value } => {
+                    self.remaining -= 1;
+                    return Some((K::from_index(index), value));
+                }
+                More { ref nodes, .. } => self.stack.push(nodes.iter()),
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+/// Both ends walk their own stack, and `remaining` stops them where they meet
+impl<'a, K: TrieKey, T> DoubleEndedIterator for Iter<'a, K, T> {
+    fn next_back(&mut self) -> Option<(K, &'a T)> {
+        if self.remaining == 0 {
+            return None;
+        }
+
+        loop {
+            let node = match self.back.last_mut()?.next_back() {
+                Some(node) => node,
+                None => {
+                    self.back.pop();
+                    continue;
+                }
+            };
+
+            match *node {
+                One { index, ref value } => {
+                    self.remaining -= 1;
+                    return Some((K::from_index(index), value));
+                }
+                More { ref nodes, .. } => self.back.push(nodes.iter()),
+            }
+        }
+    }
+}
+
+impl<'a, K: TrieKey, T> ExactSizeIterator for Iter<'a, K, T> {}
+
+impl<K: TrieKey, T: Clone> Iterator for IntoIter<K, T> {
+    type Item = (K, T);
+
+    fn next(&mut self) -> Option<(K, T)> {
+        loop {
+            let node = match self.stack.last_mut()?.next() {
+                Some(node) => node,
+                None => {
+                    self.stack.pop();
+                    continue;
+                }
+            };
+
+            match node {
+                One { index, value } => {
+                    self.remaining -= 1;
+                    return Some((K::from_index(index), value));
+                }
+                More { nodes, .. } => {
+                    self.stack.push(take_nodes(nodes, &self.placeholder).into_iter());
+                }
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<K: TrieKey, T: Clone> ExactSizeIterator for IntoIter<K, T> {}
+
+impl<'a, T> Range<'a, usize, T> {
+    fn with_key<K>(self) -> Range<'a, K, T> {
+        Range {
+            stack: self.stack,
+            start: self.start,
+            end: self.end,
+            key: PhantomData,
+        }
+    }
+}
+
+impl<'a, K: TrieKey, T> Iterator for Range<'a, K, T> {
+    type Item = (K, &'a T);
+
+    fn next(&mut self) -> Option<(K, &'a T)> {
+        loop {
+            let node = match self.stack.last_mut()?.next() {
+                Some(node) => node,
+                None => {
+                    self.stack.pop();
+                    continue;
+                }
+            };
+
+            match *node {
+                One { index, .. } if index < self.start => {}
+                One { index, ref value } => {
+                    if index > self.end {
+                        self.stack.clear();
+                        return None;
+                    }
+
+                    return Some((K::from_index(index), value));
+                }
+                More { ref nodes, .. } => self.stack.push(nodes.iter()),
+            }
+        }
+    }
+}
+
+impl<'a, K: TrieKey, T: Clone + PartialEq> Diff<'a, K, T> {
+    fn expand(&mut self, node: &'a Node<T>, other: &'a Node<T>) {
+        match (node, other) {
+            (&More { bitset, ref nodes, .. }, &More { bitset: other_bitset, nodes: ref other_nodes, .. }) => {
+                if Arc::ptr_eq(nodes, other_nodes) {
+                    return;
+                }
+
+                let union = Bitset::from(u32::from(bitset) | u32::from(other_bitset));
+                let steps: Vec<_> = union.iter().map(|idx32| {
+                    match (bitset.packed_index(idx32), other_bitset.packed_index(idx32)) {
+                        (Some(idx), Some(other_idx)) => DiffStep::Nodes(&nodes[idx], &other_nodes[other_idx]),
+                        (Some(idx), None) => DiffStep::Removed(nodes[idx].entries().with_key()),
+                        (None, Some(other_idx)) => DiffStep::Added(other_nodes[other_idx].entries().with_key()),
+                        (None, None) => unreachable!("Index should be set in either bitset"),
+                    }
+                }).collect();
+
+                self.pending.extend(steps.into_iter().rev());
+            }
+            _ => self.pending.push(DiffStep::Merge(
+                node.entries().with_key().peekable(),
+                other.entries().with_key().peekable(),
+            )),
+        }
+    }
+
+    fn merge(left: &mut Peekable<Range<'a, K, T>>, right: &mut Peekable<Range<'a, K, T>>) -> Option<Change<K, &'a T>> {
+        loop {
+            let order = match (left.peek(), right.peek()) {
+                (None, None) => return None,
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (Some(entry), Some(other)) => entry.0.cmp(&other.0),
+            };
+
+            match order {
+                Ordering::Less => return left.next().map(|(key, value)| Change::Removed(key, value)),
+                Ordering::Greater => return right.next().map(|(key, value)| Change::Added(key, value)),
+                Ordering::Equal => {
+                    let (key, value) = left.next().unwrap();
+                    let (_, other_value) = right.next().unwrap();
+
+                    if value != other_value {
+                        return Some(Change::Modified(key, value, other_value));
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl<'a, K: TrieKey, T: Clone + PartialEq> Iterator for Diff<'a, K, T> {
+    type Item = Change<K, &'a T>;
+
+    fn next(&mut self) -> Option<Change<K, &'a T>> {
+        loop {
+            let step = self.pending.last_mut()?;
+
+            if let DiffStep::Nodes(node, other) = *step {
+                self.pending.pop();
+                self.expand(node, other);
+                continue;
+            }
+
+            let change = match *step {
+                DiffStep::Nodes(..) => unreachable!("Node pairs should be expanded above"),
+                DiffStep::Removed(ref mut entries) => {
+                    entries.next().map(|(key, value)| Change::Removed(key, value))
+                }
+                DiffStep::Added(ref mut entries) => {
+                    entries.next().map(|(key, value)| Change::Added(key, value))
+                }
+                DiffStep::Merge(ref mut left, ref mut right) => Diff::merge(left, right),
+            };
+
+            if change.is_some() {
+                return change;
+            }
+
+            self.pending.pop();
+        }
+    }
+}
+
+impl<K: TrieKey, T: Clone> TrieIterMut<K, T> {
+    pub fn into_trie(self) -> KeyedTrie<K, T> {
+        self.trie.into_trie()
+    }
+}
+
+impl<'a, K: TrieKey, T: Clone> IntoIterator for &'a mut TrieIterMut<K, T> {
+    type Item = (K, &'a mut T);
+    type IntoIter = IterMut<'a, K, T>;
+
+    fn into_iter(self) -> IterMut<'a, K, T> {
+        IterMut {
+            root: Some(&mut self.trie.root),
+            stack: Vec::new(),
+            key: PhantomData,
+        }
+    }
+}
+
+impl<'a, K, T> IterMut<'a, K, T> {
+    fn visit(&mut self, node: &'a mut NodeMut<T>) -> Option<(usize, &'a mut T)> {
+        match *node {
+            Empty => None,
+            Imut(One { index, ref mut value }) => Some((index, value)),
+            Imut(More { .. }) => unreachable!("TrieIterMut should own every node"),
+            MoreMut(ref mut pairs) => {
+                self.stack.push(pairs.iter_mut());
+                None
+            }
+        }
+    }
+}
+
+impl<'a, K: TrieKey, T> Iterator for IterMut<'a, K, T> {
+    type Item = (K, &'a mut T);
+
+    fn next(&mut self) -> Option<(K, &'a mut T)> {
+        if let Some(root) = self.root.take() {
+            if let Some((index, value)) = self.visit(root) {
+                return Some((K::from_index(index), value));
+            }
+        }
+
+        loop {
+            let node = match self.stack.last_mut()?.next() {
+                Some(&mut (_, ref mut node)) => node,
+                None => {
+                    self.stack.pop();
+                    continue;
+                }
+            };
+
+            if let Some((index, value)) = self.visit(node) {
+                return Some((K::from_index(index), value));
+            }
+        }
+    }
+}
+
+impl<T> Node<T> {
+    /// Inner node over `nodes`, caching the number of entries below them
+    fn more(bitset: Bitset, nodes: Children<T>) -> Node<T> {
+        let length = nodes.iter().map(Node::len).sum();
+        More { bitset, nodes, length }
+    }
+
+    fn len(&self) -> usize {
+        match *self {
+            One { .. } => 1,
+            More { length, .. } => length,
+        }
+    }
+
+    /// Key of the entry with `n` smaller keys below, which must be `< len()`
+    ///
+    /// Each level skips whole children by their cached lengths.
+    fn nth_key(&self, mut n: usize) -> usize {
+        let mut node = self;
+
+        loop {
+            match *node {
+                One { index, .. } => return index,
+                More { ref nodes, .. } => {
+                    node = nodes.iter()
+                        .find(|child| match n.checked_sub(child.len()) {
+                            Some(rest) => {
+                                n = rest;
+                                false
+                            }
+                            None => true,
+                        })
+                        .expect("Position should be within the cached length");
+                }
+            }
+        }
+    }
+}
+
+impl<T: Clone> Node<T> {
+    fn get(&self, depth: usize, query: usize) -> Option<&T> {
+        match *self {
+            One { index, ref value } if index == query => Some(value),
+            One { .. } => None,
+            More { bitset, ref nodes, .. } => {
+                bitset.packed_index(Index32::convert(query, depth))
+                    .and_then(|idx| nodes[idx].get(depth + 1, query))
+            }
+        }
+    }
+
+    fn replace(&self, depth: usize, query: usize, value: T) -> Option<Node<T>> {
+        match *self {
+            One { index, .. } if index == query => Some(One { index, value }),
+            One { .. } => None,
+            More { bitset, ref nodes, .. } => {
+                let idx = bitset.packed_index(Index32::convert(query, depth))?;
+                let node = nodes[idx].replace(depth + 1, query, value)?;
+
+                let mut nodes = nodes.to_vec();
+                nodes[idx] = node;
+
+                Some(Node::more(bitset, Arc::from(nodes)))
+            }
+        }
+    }
+
+    fn next_empty(&self, depth: usize, start: usize, step: usize) -> Option<usize> {
+        match *self {
+            One { index, .. } =>  {
+                if index != start {
+                    Some(start)
+                } else if start == (start | low_mask(depth)) & !(step - 1) {
+                    None
+                } else {
+                    Some(start + step)
+                }
+            }
+            More { bitset, ref nodes, .. } => {
+                let first = Index32::convert(start, depth).num();
+                let last = Index32::max_with(depth).num();
+                let base = start & !low_mask(depth);
+
+                for idx32 in (first..last + 1).map(Index32::new) {
+                    let start = if idx32.num() == first {
+                        start
+                    } else {
+                        base | idx32.num() << Index32::shift(depth)
+                    };
+
+                    if start & (step - 1) != 0 {
+                        continue;
+                    }
+
+                    match bitset.packed_index(idx32) {
+                        None => return Some(start),
+                        Some(idx) => {
+                            if let Some(res) = nodes[idx].next_empty(depth + 1, start, step) {
+                                return Some(res);
+                            }
+                        }
+                    }
+                }
+
+                None
+            }
+        }
+    }
+
+    fn min_key(&self) -> usize {
+        match *self {
+            One { index, .. } => index,
+            More { bitset, ref nodes, .. } => {
+                let first = bitset.first_set().expect("Inner nodes should not be empty");
+                nodes[bitset.count_below(first)].min_key()
+            }
+        }
+    }
+
+    fn max_key(&self) -> usize {
+        match *self {
+            One { index, .. } => index,
+            More { bitset, ref nodes, .. } => {
+                let last = bitset.last_set().expect("Inner nodes should not be empty");
+                nodes[bitset.count_below(last)].max_key()
+            }
+        }
+    }
+
+    fn first(&self) -> (usize, &T) {
+        match *self {
+            One { index, ref value } => (index, value),
+            More { ref nodes, .. } => nodes[0].first(),
+        }
+    }
+
+    fn last(&self) -> (usize, &T) {
+        match *self {
+            One { index, ref value } => (index, value),
+            More { ref nodes, .. } => nodes[nodes.len() - 1].last(),
+        }
+    }
+
+    /// Entry with the least key `>= start`, assuming `start` is within the range of this node
+    fn ceil(&self, depth: usize, start: usize) -> Option<(usize, &T)> {
+        match *self {
+            One { index, ref value } if index >= start => Some((index, value)),
+            One { .. } => None,
+            More { bitset, ref nodes, .. } => {
+                let idx32 = Index32::convert(start, depth);
+
+                if let Some(idx) = bitset.packed_index(idx32) {
+                    if let Some(entry) = nodes[idx].ceil(depth + 1, start) {
+                        return Some(entry);
+                    }
+                }
+
+                bitset.mask_above(idx32).first_set()
+                    .and_then(|next| bitset.packed_index(next))
+                    .map(|idx| nodes[idx].first())
+            }
+        }
+    }
+
+    /// Entry with the greatest key `<= end`, assuming `end` is within the range of this node
+    fn floor(&self, depth: usize, end: usize) -> Option<(usize, &T)> {
+        match *self {
+            One { index, ref value } if index <= end => Some((index, value)),
+            One { .. } => None,
+            More { bitset, ref nodes, .. } => {
+                let idx32 = Index32::convert(end, depth);
+
+                if let Some(idx) = bitset.packed_index(idx32) {
+                    if let Some(entry) = nodes[idx].floor(depth + 1, end) {
+                        return Some(entry);
+                    }
+                }
+
+                bitset.mask_below(idx32).last_set()
+                    .and_then(|prev| bitset.packed_index(prev))
+                    .map(|idx| nodes[idx].last())
+            }
+        }
+    }
+
+    fn entries(&self) -> Range<'_, usize, T> {
+        Range {
+            stack: vec![slice::from_ref(self).iter()],
+            start: 0,
+            end: usize::MAX,
+            key: PhantomData,
+        }
+    }
+
+    fn range(&self, start: usize, end: usize) -> Range<'_, usize, T> {
+        self.range_at(0, start, end)
+    }
+
+    /// Entries within `start ..= end` of a node at `depth`
+    ///
+    /// Keys of the node below `start` must share its prefix with `start`,
+    /// so callers clamp `start` to the prefix of the node first.
+    fn range_at(&self, depth: usize, start: usize, end: usize) -> Range<'_, usize, T> {
+        Range {
+            stack: self.seek(depth, start),
+            start,
+            end,
+            key: PhantomData,
+        }
+    }
+
+    /// Whether the entries within `start ..= end` are the same in both nodes
+    fn range_eq(&self, other: &Node<T>, depth: usize, prefix: usize, start: usize, end: usize) -> bool
+    where
+        T: PartialEq,
+    {
+        let last = prefix | low_mask(depth);
+
+        if start <= prefix && last <= end {
+            return self == other;
+        }
+
+        match (self, other) {
+            (&More { bitset, ref nodes, .. }, &More { bitset: other_bitset, nodes: ref other_nodes, .. }) => {
+                if Arc::ptr_eq(nodes, other_nodes) {
+                    return true;
+                }
+
+                let lo = if start <= prefix { Index32::new(0) } else { Index32::convert(start, depth) };
+                let hi = if last <= end { Index32::max_with(depth) } else { Index32::convert(end, depth) };
+                let within = Bitset::from(u32::from(bitset.mask_between(lo, hi)) | u32::from(other_bitset.mask_between(lo, hi)));
+
+                // A boundary child present on one side only may still hold
+                // nothing but keys outside the range
+                within.iter().all(|idx32| {
+                    match (bitset.packed_index(idx32), other_bitset.packed_index(idx32)) {
+                        (Some(idx), Some(other_idx)) => {
+                            let prefix = prefix | idx32.num() << Index32::shift(depth);
+                            nodes[idx].range_eq(&other_nodes[other_idx], depth + 1, prefix, start, end)
+                        }
+                        (Some(idx), None) => {
+                            let prefix = prefix | idx32.num() << Index32::shift(depth);
+                            nodes[idx].range_at(depth + 1, start.max(prefix), end).next().is_none()
+                        }
+                        (None, Some(other_idx)) => {
+                            let prefix = prefix | idx32.num() << Index32::shift(depth);
+                            other_nodes[other_idx].range_at(depth + 1, start.max(prefix), end).next().is_none()
+                        }
+                        (None, None) => unreachable!("Index should be set in either bitset"),
+                    }
+                })
+            }
+            _ => {
+                let start = start.max(prefix);
+                self.range_at(depth, start, end).eq(other.range_at(depth, start, end))
+            }
+        }
+    }
+
+    fn first_difference(&self, other: &Node<T>, depth: usize) -> Option<usize> where T: PartialEq {
+        match (self, other) {
+            (&More { bitset, ref nodes, .. }, &More { bitset: other_bitset, nodes: ref other_nodes, .. }) => {
+                if Arc::ptr_eq(nodes, other_nodes) {
+                    return None;
+                }
+
+                let union = Bitset::from(u32::from(bitset) | u32::from(other_bitset));
+
+                union.iter().find_map(|idx32| {
+                    match (bitset.packed_index(idx32), other_bitset.packed_index(idx32)) {
+                        (Some(idx), Some(other_idx)) => {
+                            nodes[idx].first_difference(&other_nodes[other_idx], depth + 1)
+                        }
+                        (Some(idx), None) => Some(nodes[idx].min_key()),
+                        (None, Some(other_idx)) => Some(other_nodes[other_idx].min_key()),
+                        (None, None) => unreachable!("Index should be set in either bitset"),
+                    }
+                })
+            }
+            (&One { index, ref value }, &One { index: other_index, value: ref other_value }) => {
+                if index != other_index {
+                    Some(index.min(other_index))
+                } else if value != other_value {
+                    Some(index)
+                } else {
+                    None
+                }
+            }
+            (&One { index, ref value }, more) | (more, &One { index, ref value }) => {
+                let (min, min_value) = more.first();
+
+                if min != index {
+                    Some(min.min(index))
+                } else if min_value != value {
+                    Some(index)
+                } else {
+                    more.ceil(depth, index + 1).map(|(next, _)| next)
+                }
+            }
+        }
+    }
+
+    /// Iterator stack of a node at `depth`, positioned at the first node which may hold keys `>= start`
+    ///
+    /// Leaves on the path of `start` may still be below it,
+    /// but every subtree before the path is skipped.
+    fn seek(&self, mut depth: usize, start: usize) -> Vec<slice::Iter<'_, Node<T>>> {
+        let mut stack = Vec::new();
+        let mut current = slice::from_ref(self);
+
+        while let Some((&More { bitset, ref nodes, .. }, rest)) = current.split_first() {
+            let idx32 = Index32::convert(start, depth);
+            let skip = bitset.count_below(idx32);
+
+            stack.push(rest.iter());
+            current = &nodes[skip..];
+
+            if !bitset.get(idx32) {
+                break;
+            }
+
+            depth += 1;
+        }
+
+        stack.push(current.iter());
+        stack
+    }
+
+    fn key_range(&self) -> (usize, usize) {
+        match *self {
+            One { index, .. } => (index, index),
+            More { ref nodes, .. } if nodes.len() == 1 => nodes[0].key_range(),
+            More { ref nodes, .. } => (nodes[0].min_key(), nodes[nodes.len() - 1].max_key()),
+        }
+    }
+
+    fn subtree(&self, depth: usize, prefix: usize, prefix_depth: usize) -> Option<Node<T>> {
+        match *self {
+            One { index, .. } => {
+                if (index ^ prefix) & !low_mask(prefix_depth) == 0 {
+                    Some(self.clone())
+                } else {
+                    None
+                }
+            }
+            More { .. } if depth == prefix_depth => Some(self.clone()),
+            More { bitset, ref nodes, .. } => {
+                let idx32 = Index32::convert(prefix, depth);
+                let idx = bitset.packed_index(idx32)?;
+
+                nodes[idx].subtree(depth + 1, prefix, prefix_depth).map(|node| match node {
+                    One { .. } => node,
+                    More { .. } => {
+                        let mut bitset = Bitset::new();
+                        bitset.set(idx32);
+
+                        Node::more(bitset, Arc::from(vec![node]))
+                    }
+                })
+            }
+        }
+    }
+
+    fn restrict_to<U: Clone>(&self, depth: usize, keys: &Node<U>) -> Option<Node<T>> {
+        match (self, keys) {
+            (&One { index, .. }, _) => keys.get(depth, index).map(|_| self.clone()),
+            (&More { .. }, &One { index, .. }) => {
+                self.get(depth, index).map(|value| One { index, value: value.clone() })
+            }
+            (&More { bitset, ref nodes, .. }, &More { bitset: keys_bitset, nodes: ref keys_nodes, .. }) => {
+                let common = Bitset::from(u32::from(bitset) & u32::from(keys_bitset));
+                let mut kept = (Bitset::new(), Vec::new());
+
+                for idx32 in common.iter() {
+                    let node = &nodes[bitset.packed_index(idx32).unwrap()];
+                    let keys = &keys_nodes[keys_bitset.packed_index(idx32).unwrap()];
+
+                    if let Some(node) = node.restrict_to(depth + 1, keys) {
+                        kept.0.set(idx32);
+                        kept.1.push(node);
+                    }
+                }
+
+                collapse(kept.0, kept.1)
+            }
+        }
+    }
+
+    /// Node holding every key with the given prefix, if any
+    fn find_prefix(&self, depth: usize, prefix: usize, prefix_depth: usize) -> Option<&Node<T>> {
+        match *self {
+            One { index, .. } if (index ^ prefix) & !low_mask(prefix_depth) == 0 => Some(self),
+            One { .. } => None,
+            More { .. } if depth == prefix_depth => Some(self),
+            More { bitset, ref nodes, .. } => {
+                let idx = bitset.packed_index(Index32::convert(prefix, depth))?;
+                nodes[idx].find_prefix(depth + 1, prefix, prefix_depth)
+            }
+        }
+    }
+
+    /// Entries of both nodes, taking the values of `other` on equal keys
+    fn overwrite(&self, depth: usize, other: &Node<T>) -> Node<T> {
+        match (self, other) {
+            (_, &One { index, ref value }) => {
+                let mut node = Imut(self.clone());
+                infallible(node.insert(depth, index, value.clone()));
+                node.into_node().unwrap()
+            }
+            (&One { index, ref value }, &More { .. }) => {
+                if other.get(depth, index).is_some() {
+                    return other.clone();
+                }
+
+                let mut node = Imut(other.clone());
+                infallible(node.insert(depth, index, value.clone()));
+                node.into_node().unwrap()
+            }
+            (&More { bitset, ref nodes, .. }, &More { bitset: other_bitset, nodes: ref other_nodes, .. }) => {
+                let merged = Bitset::from(u32::from(bitset) | u32::from(other_bitset));
+
+                Node::more(merged, merged.iter().map(|idx32| {
+                    match (bitset.packed_index(idx32), other_bitset.packed_index(idx32)) {
+                        (Some(idx), Some(other_idx)) => nodes[idx].overwrite(depth + 1, &other_nodes[other_idx]),
+                        (Some(idx), None) => nodes[idx].clone(),
+                        (None, Some(other_idx)) => other_nodes[other_idx].clone(),
+                        (None, None) => unreachable!(),
+                    }
+                }).collect())
+            }
+        }
+    }
+
+    fn dedup_values(&self, interned: &mut HashSet<T>) -> Node<T> where T: Eq + Hash {
+        match *self {
+            One { index, ref value } => {
+                let value = match interned.get(value) {
+                    Some(value) => value.clone(),
+                    None => {
+                        interned.insert(value.clone());
+                        value.clone()
+                    }
+                };
+
+                One { index, value }
+            }
+            More { bitset, ref nodes, length } => More {
+                bitset,
+                nodes: nodes.iter().map(|node| node.dedup_values(interned)).collect(),
+                length,
+            },
+        }
+    }
+
+    fn try_map<U, E, F>(&self, f: &mut F) -> Result<Node<U>, E>
+    where
+        F: FnMut(usize, &T) -> Result<U, E>,
+    {
+        match *self {
+            One { index, ref value } => Ok(One { index, value: f(index, value)? }),
+            More { bitset, ref nodes, length } => Ok(More {
+                bitset,
+                nodes: nodes.iter().map(|node| node.try_map(f)).collect::<Result<_, E>>()?,
+                length,
+            }),
+        }
+    }
+
+    fn try_for_each<B, F>(&self, f: &mut F) -> ControlFlow<B>
+    where
+        F: FnMut(usize, &T) -> ControlFlow<B>,
+    {
+        match *self {
+            One { index, ref value } => f(index, value),
+            More { ref nodes, .. } => {
+                for node in nodes.iter() {
+                    node.try_for_each(f)?;
+                }
+
+                ControlFlow::Continue(())
+            }
+        }
+    }
+
+    /// Entries with keys `<= end`, sharing the children entirely below it
+    fn keep_until(&self, depth: usize, end: usize) -> Option<Node<T>> {
+        match *self {
+            One { index, .. } if index <= end => Some(self.clone()),
+            One { .. } => None,
+            More { bitset, ref nodes, .. } => {
+                let idx32 = Index32::convert(end, depth);
+                let mut kept = (bitset.mask_below(idx32), nodes[..bitset.count_below(idx32)].to_vec());
+
+                if let Some(node) = bitset.packed_index(idx32).and_then(|idx| nodes[idx].keep_until(depth + 1, end)) {
+                    kept.0.set(idx32);
+                    kept.1.push(node);
+                }
+
+                collapse(kept.0, kept.1)
+            }
+        }
+    }
+
+    /// Entries with keys `>= start`, sharing the children entirely above it
+    fn keep_from(&self, depth: usize, start: usize) -> Option<Node<T>> {
+        match *self {
+            One { index, .. } if index >= start => Some(self.clone()),
+            One { .. } => None,
+            More { bitset, ref nodes, .. } => {
+                let idx32 = Index32::convert(start, depth);
+                let above = bitset.count_below(idx32) + bitset.get(idx32) as usize;
+                let mut kept = (bitset.mask_above(idx32), Vec::new());
+
+                if let Some(node) = bitset.packed_index(idx32).and_then(|idx| nodes[idx].keep_from(depth + 1, start)) {
+                    kept.0.set(idx32);
+                    kept.1.push(node);
+                }
+
+                kept.1.extend_from_slice(&nodes[above..]);
+
+                collapse(kept.0, kept.1)
+            }
+        }
+    }
+
+    fn reduce_values<F: Fn(&T, &T) -> T>(&self, f: &F) -> T {
+        match *self {
+            One { ref value, .. } => value.clone(),
+            More { ref nodes, .. } => {
+                let mut acc = nodes[0].reduce_values(f);
+
+                for node in nodes[1..].iter() {
+                    acc = f(&acc, &node.reduce_values(f));
+                }
+
+                acc
+            }
+        }
+    }
+
+    fn map_reduce<B, F, G>(&self, map: &mut F, reduce: &mut G) -> B
+    where
+        F: FnMut(usize, &T) -> B,
+        G: FnMut(B, B) -> B,
+    {
+        match *self {
+            One { index, ref value } => map(index, value),
+            More { ref nodes, .. } => {
+                let mut acc = nodes[0].map_reduce(map, reduce);
+
+                for node in nodes[1..].iter() {
+                    let next = node.map_reduce(map, reduce);
+                    acc = reduce(acc, next);
+                }
+
+                acc
+            }
+        }
+    }
+
+    /// Number of entries if this node and the subtree below are canonical
+    fn validate(&self, depth: usize, prefix: usize) -> Option<usize> {
+        match *self {
+            One { index, .. } => {
+                if index & !low_mask(depth) == prefix {
+                    Some(1)
+                } else {
+                    None
+                }
+            }
+            More { bitset, ref nodes, length } => {
+                if depth >= MAX_DEPTH || bitset.iter().count() != nodes.len() {
+                    return None;
+                }
+
+                if let [One { .. }] = **nodes {
+                    return None;
+                }
+
+                let mut count = 0;
+
+                for (idx32, node) in bitset.iter().zip(nodes.iter()) {
+                    let prefix = prefix | idx32.num() << Index32::shift(depth);
+                    count += node.validate(depth + 1, prefix)?;
+                }
+
+                if count == 0 || count != length {
+                    None
+                } else {
+                    Some(count)
+                }
+            }
+        }
+    }
+
+    fn mask_levels(&self, depth: usize, masks: &[Bitset]) -> Option<Node<T>> {
+        match *self {
+            One { index, .. } => {
+                let allowed = masks.iter().enumerate().skip(depth)
+                    .all(|(depth, mask)| mask.get(Index32::convert(index, depth)));
+
+                if allowed {
+                    Some(self.clone())
+                } else {
+                    None
+                }
+            }
+            More { .. } if depth >= masks.len() => Some(self.clone()),
+            More { bitset, ref nodes, .. } => {
+                let allowed = Bitset::from(u32::from(bitset) & u32::from(masks[depth]));
+                let mut kept = (Bitset::new(), Vec::new());
+
+                for idx32 in allowed.iter() {
+                    let node = &nodes[bitset.count_below(idx32)];
+
+                    if let Some(node) = node.mask_levels(depth + 1, masks) {
+                        kept.0.set(idx32);
+                        kept.1.push(node);
+                    }
+                }
+
+                collapse(kept.0, kept.1)
+            }
+        }
+    }
+
+    fn retain_in_ranges(&self, depth: usize, prefix: usize, ranges: &[ops::Range<usize>]) -> Option<Node<T>> {
+        let last = prefix | low_mask(depth);
+        let ranges = &ranges[ranges.partition_point(|range| range.end <= prefix)..];
+        let ranges = &ranges[..ranges.partition_point(|range| range.start <= last)];
+
+        match (ranges, self) {
+            ([], _) => None,
+            ([range], _) if range.start <= prefix && range.end > last => Some(self.clone()),
+            (_, &One { index, .. }) => {
+                if ranges.iter().any(|range| range.contains(&index)) {
+                    Some(self.clone())
+                } else {
+                    None
+                }
+            }
+            (_, &More { bitset, ref nodes, .. }) => {
+                let mut kept = (Bitset::new(), Vec::new());
+
+                for (idx32, node) in bitset.iter().zip(nodes.iter()) {
+                    let prefix = prefix | idx32.num() << Index32::shift(depth);
+
+                    if let Some(node) = node.retain_in_ranges(depth + 1, prefix, ranges) {
+                        kept.0.set(idx32);
+                        kept.1.push(node);
+                    }
+                }
+
+                collapse(kept.0, kept.1)
+            }
+        }
+    }
+
+    /// Returns `None` if every entry is kept, or the remaining node otherwise
+    fn retain<F: FnMut(usize, &T) -> bool>(&self, f: &mut F, removed: &mut usize) -> Option<Option<Node<T>>> {
+        match *self {
+            One { index, ref value } => {
+                if f(index, value) {
+                    None
+                } else {
+                    *removed += 1;
+                    Some(None)
+                }
+            }
+            More { bitset, ref nodes, .. } => {
+                let mut changed = false;
+                let mut kept = (Bitset::new(), Vec::new());
+
+                for (idx32, node) in bitset.iter().zip(nodes.iter()) {
+                    let node = match node.retain(f, removed) {
+                        None => Some(node.clone()),
+                        Some(node) => {
+                            changed = true;
+                            node
+                        }
+                    };
+
+                    if let Some(node) = node {
+                        kept.0.set(idx32);
+                        kept.1.push(node);
+                    }
+                }
+
+                if changed {
+                    Some(collapse(kept.0, kept.1))
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    fn retain_into<F: FnMut(usize, &T) -> bool>(
+        self,
+        f: &mut F,
+        placeholder: &Arc<[Node<T>]>,
+    ) -> (Option<Node<T>>, Option<Node<T>>) {
+        match self {
+            One { index, value } => {
+                if f(index, &value) {
+                    (Some(One { index, value }), None)
+                } else {
+                    (None, Some(One { index, value }))
+                }
+            }
+            More { bitset, nodes, .. } => {
+                let mut kept = (Bitset::new(), Vec::new());
+                let mut removed = (Bitset::new(), Vec::new());
+
+                for (idx32, node) in bitset.iter().zip(take_nodes(nodes, placeholder)) {
+                    let (kept_node, removed_node) = node.retain_into(f, placeholder);
+
+                    if let Some(node) = kept_node {
+                        kept.0.set(idx32);
+                        kept.1.push(node);
+                    }
+
+                    if let Some(node) = removed_node {
+                        removed.0.set(idx32);
+                        removed.1.push(node);
+                    }
+                }
+
+                (collapse(kept.0, kept.1), collapse(removed.0, removed.1))
+            }
+        }
+    }
+
+    /// Remove the sorted `keys` within the range of this node, consuming them
+    ///
+    /// Returns `None` if nothing was removed, or the remaining node otherwise.
+    fn remove_sorted<I: Iterator<Item=usize>>(
+        &self,
+        depth: usize,
+        prefix: usize,
+        keys: &mut Peekable<I>,
+        removed: &mut usize,
+    ) -> Option<Option<Node<T>>> {
+        let in_range = |key: usize| key & !low_mask(depth) == prefix;
+
+        match *self {
+            One { index, .. } => {
+                let mut found = false;
+
+                while let Some(key) = keys.next_if(|&key| in_range(key)) {
+                    found |= key == index;
+                }
+
+                if found {
+                    *removed += 1;
+                    Some(None)
+                } else {
+                    None
+                }
+            }
+            More { bitset, ref nodes, .. } => {
+                let mut changed = false;
+                let mut kept = (Bitset::new(), Vec::new());
+
+                for (idx32, node) in bitset.iter().zip(nodes.iter()) {
+                    let prefix = prefix | idx32.num() << Index32::shift(depth);
+
+                    while keys.next_if(|&key| in_range(key) && Index32::convert(key, depth) < idx32).is_some() {}
+
+                    let node = match keys.peek() {
+                        Some(&key) if key & !low_mask(depth + 1) == prefix => {
+                            match node.remove_sorted(depth + 1, prefix, keys, removed) {
+                                None => Some(node.clone()),
+                                Some(node) => {
+                                    changed = true;
+                                    node
+                                }
+                            }
+                        }
+                        _ => Some(node.clone()),
+                    };
+
+                    if let Some(node) = node {
+                        kept.0.set(idx32);
+                        kept.1.push(node);
+                    }
+                }
+
+                while keys.next_if(|&key| in_range(key)).is_some() {}
 
-    pub fn to_mut(&self) -> TrieMut<T> {
-        TrieMut {
-            root: match self.root {
-                Some(ref node) => Imut(node.clone()),
-                None => Empty,
-            },
+                if changed {
+                    Some(collapse(kept.0, kept.1))
+                } else {
+                    None
+                }
+            }
         }
     }
 
-    pub fn update_all<I: IntoIterator<Item=(usize, T)>>(&self, iter: I) -> Self {
-        let mut iter = iter.into_iter();
+    fn merkle_hash(&self, cache: &mut MerkleCache<T>) -> u64 where T: Hash {
+        let mut hasher = DefaultHasher::new();
 
-        match iter.next() {
-            None => self.clone(),
-            Some((index, value)) => {
-                let mut trie_mut = self.to_mut();
-                trie_mut.insert(index, value);
+        match *self {
+            One { index, ref value } => {
+                0u8.hash(&mut hasher);
+                index.hash(&mut hasher);
+                value.hash(&mut hasher);
+            }
+            More { bitset, ref nodes, .. } => {
+                if let Some(&(_, hash)) = cache.hashes.get(&(nodes.as_ptr() as usize)) {
+                    return hash;
+                }
 
-                for (index, value) in iter {
-                    trie_mut.insert(index, value);
+                1u8.hash(&mut hasher);
+                bitset.num().hash(&mut hasher);
+
+                for node in nodes.iter() {
+                    node.merkle_hash(cache).hash(&mut hasher);
                 }
 
-                trie_mut.into_trie()
+                let hash = hasher.finish();
+                cache.hashes.insert(nodes.as_ptr() as usize, (nodes.clone(), hash));
+                return hash;
             }
         }
-    }
 
-    pub fn update(&self, index: usize, value: T) -> Self {
-        let mut trie_mut = self.to_mut();
-        trie_mut.insert(index, value);
-        trie_mut.into_trie()
+        hasher.finish()
     }
 
-    pub fn remove_all<I: IntoIterator<Item=usize>>(&self, iter: I) -> Self {
-        let mut iter = iter.into_iter();
+    /// Push the membership of the sorted `keys` within the range of this node, consuming them
+    fn contains_sorted<I: Iterator<Item=usize>>(
+        &self,
+        depth: usize,
+        prefix: usize,
+        keys: &mut Peekable<I>,
+        res: &mut Vec<bool>,
+    ) {
+        let in_range = |key: usize| key & !low_mask(depth) == prefix;
 
-        match iter.next() {
-            None => self.clone(),
-            Some(index) => {
-                let mut trie_mut = self.to_mut();
-                trie_mut.remove(index);
+        match *self {
+            One { index, .. } => {
+                while let Some(key) = keys.next_if(|&key| in_range(key)) {
+                    res.push(key == index);
+                }
+            }
+            More { bitset, ref nodes, .. } => {
+                while let Some(&key) = keys.peek().filter(|&&key| in_range(key)) {
+                    let idx32 = Index32::convert(key, depth);
+
+                    match bitset.packed_index(idx32) {
+                        Some(idx) => {
+                            let prefix = prefix | idx32.num() << Index32::shift(depth);
+                            nodes[idx].contains_sorted(depth + 1, prefix, keys, res);
+                        }
+                        None => {
+                            keys.next();
+                            res.push(false);
+                        }
+                    }
+                }
+            }
+        }
+    }
 
-                for index in iter {
-                    trie_mut.remove(index);
+    fn visit_allocations(&self, visited: &mut HashSet<*const Node<T>>) {
+        if let More { ref nodes, .. } = *self {
+            if visited.insert(nodes.as_ptr()) {
+                for node in nodes.iter() {
+                    node.visit_allocations(visited);
                 }
+            }
+        }
+    }
 
-                trie_mut.into_trie()
+    fn count_nodes(&self, counts: &mut (usize, usize)) {
+        match *self {
+            One { .. } => counts.0 += 1,
+            More { ref nodes, .. } => {
+                counts.1 += 1;
+
+                for node in nodes.iter() {
+                    node.count_nodes(counts);
+                }
             }
         }
     }
 
-    pub fn remove(&self, index: usize) -> Self {
-        let mut trie_mut = self.to_mut();
-        trie_mut.remove(index);
-        trie_mut.into_trie()
+    fn heap_size(&self, visited: &mut HashSet<*const Node<T>>) -> usize {
+        match *self {
+            One { .. } => 0,
+            More { ref nodes, .. } => {
+                if !visited.insert(nodes.as_ptr()) {
+                    return 0;
+                }
+
+                let ref_counts = 2 * mem::size_of::<usize>();
+                let own = ref_counts + nodes.len() * mem::size_of::<Node<T>>();
+
+                own + nodes.iter().map(|node| node.heap_size(visited)).sum::<usize>()
+            }
+        }
     }
 
-    pub fn next_empty(&self, start: usize) -> Option<usize> {
-        self.root.as_ref()
-            .map_or(Some(start), |node| {
-                node.next_empty(0, start)
-                    .or_else(|| node.next_empty(0, 0))
-            })
+    fn depth_histogram(&self, depth: usize, histogram: &mut Vec<usize>) {
+        match *self {
+            One { .. } => {
+                if histogram.len() <= depth {
+                    histogram.resize(depth + 1, 0);
+                }
+                histogram[depth] += 1;
+            }
+            More { ref nodes, .. } => {
+                for node in nodes.iter() {
+                    node.depth_histogram(depth + 1, histogram);
+                }
+            }
+        }
     }
 }
 
-impl<T: Clone> Default for Trie<T> {
-    fn default() -> Self {
-        Trie::new()
+impl<T: PartialEq> PartialEq for Node<T> {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (&One { index, ref value }, &One { index: other_index, value: ref other_value }) => {
+                index == other_index && value == other_value
+            }
+            (&More { bitset, ref nodes, .. }, &More { bitset: other_bitset, nodes: ref other_nodes, .. }) => {
+                // Subtrees shared by structural sharing are equal without traversal
+                Arc::ptr_eq(nodes, other_nodes) || (bitset == other_bitset && nodes == other_nodes)
+            }
+            _ => false,
+        }
     }
 }
 
-impl<T: Clone> Clone for Trie<T> {
+impl<T: Clone> Clone for Node<T> {
     fn clone(&self) -> Self {
-        Trie {
-            root: self.root.clone(),
-            length: self.length,
+        match *self {
+            One { index, ref value } => One { index, value: value.clone() },
+            More { bitset, ref nodes, length } => More { bitset, nodes: nodes.clone(), length },
         }
     }
 }
 
-impl<T: Clone> Extend<(usize, T)> for Trie<T> {
-    fn extend<I: IntoIterator<Item=(usize, T)>>(&mut self, iter: I) {
-        let mut trie_mut = self.to_mut();
-        trie_mut.extend(iter);
-        *self = trie_mut.into_trie();
-    }
+/// Whether `index` may be a key of the trie summarized by `bloom`
+///
+/// `bloom` should come from `Trie::membership_bloom`. `false` means
+/// the key is definitely absent, while `true` may be a false positive.
+pub fn bloom_may_contain<K: TrieKey>(bloom: &[u64], key: K) -> bool {
+    !bloom.is_empty() && bloom_slots(key.to_index(), bloom.len() * 64).iter()
+        .all(|slot| bloom[slot / 64] & 1 << (slot % 64) != 0)
 }
 
-impl<T: Clone> FromIterator<(usize, T)> for Trie<T> {
-    fn from_iter<I: IntoIterator<Item=(usize, T)>>(iter: I) -> Self {
-        let trie: TrieMut<T> = iter.into_iter().collect();
-        trie.into_trie()
-    }
-}
+/// Two slots among `slots` for `index`, from the halves of a mixed hash
+fn bloom_slots(index: usize, slots: usize) -> [usize; 2] {
+    let mut hash = index as u64;
+    hash = (hash ^ hash >> 30).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    hash = (hash ^ hash >> 27).wrapping_mul(0x94d0_49bb_1331_11eb);
+    hash ^= hash >> 31;
 
-impl<T: Clone> TrieMut<T> {
-    pub fn len(&self) -> usize {
-        self.root.len()
-    }
+    [(hash as u32) as usize % slots, ((hash >> 32) as u32) as usize % slots]
+}
 
-    pub fn insert(&mut self, index: usize, value: T) -> Option<T> {
-        self.root.insert(0, index, value)
+/// Mask of the bits which can vary among keys below a node at `depth`
+fn low_mask(depth: usize) -> usize {
+    match USIZE_BITS.saturating_sub(depth * 5) {
+        0 => 0,
+        USIZE_BITS => usize::MAX,
+        bits => (1 << bits) - 1,
     }
+}
 
-    pub fn remove(&mut self, index: usize) -> Option<T> {
-        self.root.remove(0, index)
-    }
+/// Distance between adjacent keys of type `K` in the index space
+fn key_step<K: TrieKey>() -> usize {
+    1 << (USIZE_BITS - K::BITS)
+}
 
-    pub fn into_trie(self) -> Trie<T> {
-        let root = self.root.into_node();
-        let length = root.as_ref().map_or(0, |node| node.len());
+/// Pass `keys` through, checking that they are in ascending order in debug builds
+fn assert_sorted<I: IntoIterator<Item=usize>>(keys: I) -> impl Iterator<Item=usize> {
+    let mut prev = None;
 
-        Trie {
-            root,
-            length,
-        }
-    }
+    keys.into_iter().inspect(move |&key| {
+        debug_assert!(prev.is_none_or(|prev| prev <= key),
+            "Keys should be sorted in ascending order");
+        prev = Some(key);
+    })
 }
 
-impl<T: Clone> Extend<(usize, T)> for TrieMut<T> {
-    fn extend<I: IntoIterator<Item=(usize, T)>>(&mut self, iter: I) {
-        for (index, value) in iter {
-            self.insert(index, value);
-        }
+/// Inclusive bounds of `range`, or `None` if it's empty
+fn bounds<R: RangeBounds<usize>>(range: &R) -> Option<(usize, usize)> {
+    let start = match range.start_bound() {
+        Bound::Included(&start) => start,
+        Bound::Excluded(&start) => start.checked_add(1)?,
+        Bound::Unbounded => 0,
+    };
+    let end = match range.end_bound() {
+        Bound::Included(&end) => end,
+        Bound::Excluded(&end) => end.checked_sub(1)?,
+        Bound::Unbounded => usize::MAX,
+    };
+
+    if start <= end {
+        Some((start, end))
+    } else {
+        None
     }
 }
 
-impl<T: Clone> FromIterator<(usize, T)> for TrieMut<T> {
-    fn from_iter<I: IntoIterator<Item=(usize, T)>>(iter: I) -> Self {
-        let mut trie_mut = Trie::default().to_mut();
-        trie_mut.extend(iter);
-        trie_mut
-    }
-}
+fn make_mut<T: Clone>(
+    bitset: Bitset,
+    nodes: &[Node<T>],
+) -> Result<Vec<(Index32, NodeMut<T>)>, TryReserveError> {
+    let mut pairs = Vec::new();
+    pairs.try_reserve(nodes.len() + 1)?;
 
-impl<T: Clone> Node<T> {
-    fn len(&self) -> usize {
-        match *self {
-            One { .. } => 1,
-            More { ref nodes, .. } => nodes.iter().map(|v| v.len()).sum(),
-        }
-    }
+    pairs.extend(bitset.iter()
+        .zip(nodes)
+        .map(|(idx, value)| (idx, Imut(value.clone()))));
 
-    fn get(&self, index: usize) -> Option<T> {
-        let query = index;
+    Ok(pairs)
+}
 
-        match *self {
-            One { ref value, .. } => Some(value.clone()),
-            More { bitset, ref nodes } => {
-                bitset.packed_index(Index32::new(query % 32))
-                    .and_then(|idx| nodes[idx].get(query / 32))
+fn build_sorted<K: TrieKey, T: Clone>(depth: usize, entries: &[(K, T)]) -> Option<Node<T>> {
+    match *entries {
+        [] => None,
+        [(key, ref value)] => Some(One { index: key.to_index(), value: value.clone() }),
+        _ => {
+            let mut bitset = Bitset::new();
+            let mut nodes = Vec::new();
+            let mut rest = entries;
+
+            while let Some(&(key, _)) = rest.first() {
+                let idx32 = Index32::convert(key.to_index(), depth);
+                let run = rest.iter()
+                    .take_while(|entry| Index32::convert(entry.0.to_index(), depth) == idx32)
+                    .count();
+
+                bitset.set(idx32);
+                nodes.extend(build_sorted(depth + 1, &rest[..run]));
+                rest = &rest[run..];
             }
-        }
-    }
 
-    fn next_empty(&self, depth: usize, start: usize) -> Option<usize> {
-        if depth > MAX_DEPTH {
-            return None;
+            Some(Node::more(bitset, Arc::from(nodes)))
         }
+    }
+}
 
-        let transform = |idx| Index32::convert(idx, depth);
-
-        match *self {
-            One { index, .. } =>  {
-                if index != start {
-                    Some(start)
-                } else if transform(start) == Index32::max_with(depth) {
-                    None
-                } else {
-                    Some(start + 1)
-                }
-            }
-            More { bitset, ref nodes } => {
-                if !bitset.get(transform(start)) {
-                    Some(start)
-                } else {
-                    let start_iter = (start..)
-                        .take_while(|&idx| transform(idx) < Index32::max_with(depth));
-
-                    for start in start_iter {
-                        match bitset.packed_index(transform(start)) {
-                            None => return Some(start),
-                            Some(idx) => match nodes[idx].next_empty(depth + 1, start) {
-                                Some(res) => return Some(res),
-                                None => {}
-                            }
-                        }
-                    }
+/// Move the children out of `nodes` if it's not shared, or clone them otherwise
+fn take_nodes<T: Clone>(mut nodes: Arc<[Node<T>]>, placeholder: &Arc<[Node<T>]>) -> Vec<Node<T>> {
+    match Arc::get_mut(&mut nodes) {
+        Some(nodes) => nodes.iter_mut()
+            .map(|node| mem::replace(node, More {
+                bitset: Bitset::new(),
+                nodes: placeholder.clone(),
+                length: 0,
+            }))
+            .collect(),
+        None => nodes.to_vec(),
+    }
+}
 
-                    None
-                }
-            }
-        }
+/// Inner node for the given children, or the only leaf among them
+fn collapse<T>(bitset: Bitset, mut nodes: Vec<Node<T>>) -> Option<Node<T>> {
+    match nodes.len() {
+        0 => None,
+        1 if matches!(nodes[0], One { .. }) => nodes.pop(),
+        _ => Some(Node::more(bitset, Arc::from(nodes))),
     }
 }
 
-impl<T: Clone> Clone for Node<T> {
-    fn clone(&self) -> Self {
-        match *self {
-            One { index, ref value } => One { index, value: value.clone() },
-            More { bitset, ref nodes } => More { bitset, nodes: nodes.clone() },
-        }
+/// Replacement for an owned inner node which is empty or holds a single leaf
+fn collapse_mut<T: Clone>(pairs: &[(Index32, NodeMut<T>)]) -> Option<NodeMut<T>> {
+    match pairs.len() {
+        0 => Some(Empty),
+        1 => match pairs[0] {
+            (_, Imut(One { index, ref value })) => {
+                Some(Imut(One { index, value: value.clone() }))
+            }
+            _ => None,
+        },
+        _ => None,
     }
 }
 
-fn make_mut<T: Clone>(bitset: Bitset, nodes: &[Node<T>]) -> Vec<(Index32, NodeMut<T>)> {
-    bitset.iter()
-        .zip(nodes)
-        .map(|(idx, value)| (idx, Imut(value.clone())))
-        .collect()
+fn infallible<T>(res: Result<T, TryReserveError>) -> T {
+    res.unwrap_or_else(|err| panic!("{}", err))
 }
 
 impl<T: Clone> NodeMut<T> {
@@ -281,15 +3410,16 @@ impl<T: Clone> NodeMut<T> {
         match *self {
             Empty => 0,
             Imut(ref node) => node.len(),
-            MoreMut(ref nodes) => nodes.iter().map(|&(_, ref node)| node.len()).sum(),
+            MoreMut(ref nodes) => nodes.iter().map(|(_, node)| node.len()).sum(),
         }
     }
 
-    fn insert(&mut self, depth: usize, new_index: usize, new_value: T) -> Option<T> {
-        if depth > MAX_DEPTH {
-            return None;
-        }
-
+    fn insert(
+        &mut self,
+        depth: usize,
+        new_index: usize,
+        new_value: T,
+    ) -> Result<Option<T>, TryReserveError> {
         let transform = |idx| Index32::convert(idx, depth);
         let mut res = None;
 
@@ -300,32 +3430,28 @@ impl<T: Clone> NodeMut<T> {
                 Some(Imut(One { index, value: new_value }))
             }
             Imut(One { index, ref mut value }) => {
-                let mut pairs = vec![
-                    (transform(index), Imut(One {
-                        index,
-                        value: value.clone(),
-                    })),
-                    (transform(new_index), Imut(One {
-                        index: new_index,
-                        value: new_value,
-                    })),
-                ];
+                debug_assert!(depth < MAX_DEPTH, "Distinct keys must diverge above depth {}", MAX_DEPTH);
 
-                if index > new_index {
-                    pairs.swap(0, 1);
-                }
+                let mut pairs = Vec::new();
+                pairs.try_reserve(2)?;
+                pairs.push((transform(index), Imut(One {
+                    index,
+                    value: value.clone(),
+                })));
 
-                Some(MoreMut(pairs))
+                let mut node = MoreMut(pairs);
+                node.insert(depth, new_index, new_value)?;
+                Some(node)
             }
-            Imut(More { bitset, ref mut nodes }) => {
-                let mut node = MoreMut(make_mut(bitset, &nodes));
-                res = node.insert(depth, new_index, new_value);
+            Imut(More { bitset, ref mut nodes, .. }) => {
+                let mut node = MoreMut(make_mut(bitset, nodes)?);
+                res = node.insert(depth, new_index, new_value)?;
                 Some(node)
             }
             MoreMut(ref mut pairs) => {
                 match pairs.binary_search_by_key(&transform(new_index), |p| p.0) {
                     Ok(idx) => {
-                        res = pairs[idx].1.insert(depth + 1, new_index, new_value);
+                        res = pairs[idx].1.insert(depth + 1, new_index, new_value)?;
                     }
                     Err(idx) => {
                         let index32 = transform(new_index);
@@ -333,6 +3459,7 @@ impl<T: Clone> NodeMut<T> {
                             index: new_index,
                             value: new_value,
                         });
+                        pairs.try_reserve(1)?;
                         pairs.insert(idx, (index32, node));
                     }
                 }
@@ -345,14 +3472,10 @@ impl<T: Clone> NodeMut<T> {
             *self = replace;
         }
 
-        res
+        Ok(res)
     }
 
     fn remove(&mut self, depth: usize, del_index: usize) -> Option<T> {
-        if depth > MAX_DEPTH {
-            return None;
-        }
-
         let transform = |idx| Index32::convert(idx, depth);
         let mut res = None;
 
@@ -363,9 +3486,9 @@ impl<T: Clone> NodeMut<T> {
                 Some(Empty)
             }
             Imut(One { .. }) => None,
-            Imut(More { bitset, ref mut nodes }) => {
+            Imut(More { bitset, ref mut nodes, .. }) => {
                 if bitset.get(transform(del_index)) {
-                    let mut node = MoreMut(make_mut(bitset, &nodes));
+                    let mut node = MoreMut(infallible(make_mut(bitset, nodes)));
                     res = node.remove(depth, del_index);
 
                     Some(node)
@@ -385,23 +3508,211 @@ impl<T: Clone> NodeMut<T> {
                     }
                 }
 
-                if pairs.len() == 1 {
-                    if let (_, Imut(One { index, ref value })) = pairs[0] {
-                        Some(Imut(One { index, value: value.clone() }))
-                    } else {
-                        None
+                collapse_mut(pairs)
+            }
+        };
+
+        if let Some(replace) = replace {
+            *self = replace;
+        }
+
+        res
+    }
+
+    fn update_path<F: FnOnce(Option<T>) -> Option<T>>(&mut self, depth: usize, index: usize, f: F) {
+        let idx32 = Index32::convert(index, depth);
+
+        match *self {
+            Imut(One { index: found, .. }) if found == index => {
+                if let Imut(One { value, .. }) = mem::replace(self, Empty) {
+                    if let Some(value) = f(Some(value)) {
+                        *self = Imut(One { index, value });
+                    }
+                }
+
+                return;
+            }
+            Imut(More { bitset, ref nodes, .. }) if bitset.get(idx32) => {
+                *self = MoreMut(infallible(make_mut(bitset, nodes)));
+            }
+            _ => {}
+        }
+
+        match *self {
+            MoreMut(ref mut pairs) => match pairs.binary_search_by_key(&idx32, |p| p.0) {
+                Ok(pos) => {
+                    pairs[pos].1.update_path(depth + 1, index, f);
+
+                    if let (_, Empty) = pairs[pos] {
+                        pairs.remove(pos);
+                    }
+                }
+                Err(pos) => {
+                    if let Some(value) = f(None) {
+                        pairs.insert(pos, (idx32, Imut(One { index, value })));
                     }
-                } else {
-                    None
                 }
+            },
+            _ => {
+                if let Some(value) = f(None) {
+                    infallible(self.insert(depth, index, value));
+                }
+
+                return;
             }
+        }
+
+        let replace = match *self {
+            MoreMut(ref pairs) => collapse_mut(pairs),
+            _ => None,
         };
 
         if let Some(replace) = replace {
             *self = replace;
         }
+    }
 
-        res
+    fn get_mut_or_insert_with<F: FnOnce() -> T>(
+        &mut self,
+        depth: usize,
+        new_index: usize,
+        f: F,
+    ) -> &mut T {
+        let transform = |idx| Index32::convert(idx, depth);
+
+        let replace = match *self {
+            Imut(One { index, ref value }) if index != new_index => {
+                Some(MoreMut(vec![
+                    (transform(index), Imut(One {
+                        index,
+                        value: value.clone(),
+                    })),
+                ]))
+            }
+            Imut(More { bitset, ref nodes, .. }) => Some(MoreMut(infallible(make_mut(bitset, nodes)))),
+            _ => None,
+        };
+
+        if let Some(replace) = replace {
+            *self = replace;
+        }
+
+        match *self {
+            Empty => {
+                *self = Imut(One { index: new_index, value: f() });
+
+                match *self {
+                    Imut(One { ref mut value, .. }) => value,
+                    _ => unreachable!(),
+                }
+            }
+            Imut(One { ref mut value, .. }) => value,
+            Imut(More { .. }) => unreachable!(),
+            MoreMut(ref mut pairs) => {
+                let idx = match pairs.binary_search_by_key(&transform(new_index), |p| p.0) {
+                    Ok(idx) => idx,
+                    Err(idx) => {
+                        pairs.insert(idx, (transform(new_index), Empty));
+                        idx
+                    }
+                };
+
+                pairs[idx].1.get_mut_or_insert_with(depth + 1, new_index, f)
+            }
+        }
+    }
+
+    /// Fill `res[slot]` for each `(index, slot)` of `wanted` below this node
+    fn get_disjoint_mut<'a>(
+        &'a mut self,
+        depth: usize,
+        wanted: &[(usize, usize)],
+        res: &mut [Option<&'a mut T>],
+    ) {
+        if let Imut(More { bitset, ref nodes, .. }) = *self {
+            *self = MoreMut(infallible(make_mut(bitset, nodes)));
+        }
+
+        match *self {
+            Empty => {}
+            Imut(One { index, ref mut value }) => {
+                if let Some(&(_, slot)) = wanted.iter().find(|entry| entry.0 == index) {
+                    res[slot] = Some(value);
+                }
+            }
+            Imut(More { .. }) => unreachable!(),
+            MoreMut(ref mut pairs) => {
+                for &mut (idx32, ref mut node) in pairs.iter_mut() {
+                    let below: Vec<_> = wanted.iter()
+                        .filter(|entry| Index32::convert(entry.0, depth) == idx32)
+                        .cloned()
+                        .collect();
+
+                    if !below.is_empty() {
+                        node.get_disjoint_mut(depth + 1, &below, res);
+                    }
+                }
+            }
+        }
+    }
+
+    fn reserve_path(&mut self, depth: usize, index: usize) {
+        if let Imut(More { bitset, ref nodes, .. }) = *self {
+            *self = MoreMut(infallible(make_mut(bitset, nodes)));
+        }
+
+        if let MoreMut(ref mut pairs) = *self {
+            let idx32 = Index32::convert(index, depth);
+
+            if let Ok(idx) = pairs.binary_search_by_key(&idx32, |p| p.0) {
+                pairs[idx].1.reserve_path(depth + 1, index);
+            }
+        }
+    }
+
+    fn retain_returning<F: FnMut(usize, &mut T) -> bool>(&mut self, f: &mut F, removed: &mut Vec<(usize, T)>) {
+        if let Imut(More { bitset, ref nodes, .. }) = *self {
+            *self = MoreMut(infallible(make_mut(bitset, nodes)));
+        }
+
+        let replace = match *self {
+            Empty => None,
+            Imut(One { index, ref mut value }) => {
+                if f(index, value) {
+                    None
+                } else {
+                    Some(Empty)
+                }
+            }
+            Imut(More { .. }) => unreachable!("Inner node should be owned before filtering"),
+            MoreMut(ref mut pairs) => {
+                for &mut (_, ref mut node) in pairs.iter_mut() {
+                    node.retain_returning(f, removed);
+                }
+
+                pairs.retain(|pair| !matches!(pair.1, Empty));
+
+                collapse_mut(pairs)
+            }
+        };
+
+        if let Some(replace) = replace {
+            if let Imut(One { index, value }) = mem::replace(self, replace) {
+                removed.push((index, value));
+            }
+        }
+    }
+
+    fn make_mut_all(&mut self) {
+        if let Imut(More { bitset, ref nodes, .. }) = *self {
+            *self = MoreMut(infallible(make_mut(bitset, nodes)));
+        }
+
+        if let MoreMut(ref mut pairs) = *self {
+            for &mut (_, ref mut node) in pairs {
+                node.make_mut_all();
+            }
+        }
     }
 
     fn into_node(self) -> Option<Node<T>> {
@@ -419,10 +3730,7 @@ impl<T: Clone> NodeMut<T> {
                     }
                 }
 
-                Some(More {
-                    bitset,
-                    nodes: Arc::from(&nodes[..]),
-                })
+                collapse(bitset, nodes)
             }
         }
     }
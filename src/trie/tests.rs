@@ -0,0 +1,184 @@
+use std::marker::PhantomData;
+
+use bitset::{Bitset, Index32, USIZE_BITS};
+
+use super::{Trie, Node, MAX_DEPTH};
+use super::Node::*;
+
+/// Depth of every leaf below `node`, in the order of the children
+fn leaf_depths<T>(node: &Node<T>, depth: usize, depths: &mut Vec<(usize, usize)>) {
+    match *node {
+        One { index, .. } => depths.push((index, depth)),
+        More { ref nodes, .. } => {
+            for node in nodes.iter() {
+                leaf_depths(node, depth + 1, depths);
+            }
+        }
+    }
+}
+
+fn depths_of(keys: &[usize]) -> Vec<(usize, usize)> {
+    let trie: Trie<usize> = keys.iter().map(|&key| (key, key)).collect();
+    let mut depths = Vec::new();
+
+    if let Some(ref node) = trie.root {
+        leaf_depths(node, 0, &mut depths);
+    }
+
+    depths
+}
+
+#[test]
+fn max_depth_covers_every_bit() {
+    assert_eq!(MAX_DEPTH, USIZE_BITS.div_ceil(5));
+    assert_eq!(Index32::shift(MAX_DEPTH - 1), 0);
+}
+
+#[test]
+fn root_branches_on_the_highest_chunk() {
+    let high = 1 << (USIZE_BITS - 1);
+    let depths = depths_of(&[high, 0]);
+
+    assert_eq!(depths, vec![(0, 1), (high, 1)]);
+
+    match Trie::new().update(0, ()).update(high, ()).root {
+        Some(More { bitset, .. }) => {
+            let positions: Vec<_> = bitset.iter().map(|idx32| idx32.num()).collect();
+            assert_eq!(positions, vec![0, Index32::convert(high, 0).num()]);
+        }
+        _ => panic!("Root should branch"),
+    }
+}
+
+#[test]
+fn adjacent_keys_split_at_the_last_chunk() {
+    assert_eq!(depths_of(&[1, 0]), vec![(0, MAX_DEPTH), (1, MAX_DEPTH)]);
+    assert_eq!(depths_of(&[usize::MAX, usize::MAX - 1]),
+        vec![(usize::MAX - 1, MAX_DEPTH), (usize::MAX, MAX_DEPTH)]);
+}
+
+#[test]
+fn children_are_in_ascending_key_order() {
+    let keys = [40, 7, 1 << 20, 3, usize::MAX, 1 << 40, 0, 31, 32];
+    let mut sorted = keys.to_vec();
+    sorted.sort();
+
+    let order: Vec<_> = depths_of(&keys).into_iter().map(|(index, _)| index).collect();
+    assert_eq!(order, sorted);
+}
+
+#[test]
+fn get_checks_the_leaf_key() {
+    let trie = Trie::new().update(5, "five").update(1 << 40, "far");
+
+    assert_eq!(trie.get(5), Some("five"));
+    assert_eq!(trie.get(1 << 40), Some("far"));
+    assert_eq!(trie.get(4), None);
+    assert_eq!(trie.get(6), None);
+    assert_eq!(trie.get((1 << 40) + 5), None);
+}
+
+#[test]
+fn removing_every_key_empties_the_root() {
+    let keys = [0, 1, 33, 1 << 30, usize::MAX];
+    let mut trie = Trie::new();
+
+    for &key in keys.iter() {
+        trie = trie.update(key, key);
+    }
+
+    for &key in keys.iter() {
+        trie = trie.remove(key);
+        assert_eq!(trie.get(key), None);
+    }
+
+    assert!(trie.is_empty());
+    assert!(trie.root.is_none());
+
+    let mut trie_mut = Trie::new().update(1, 1).update(2, 2).to_mut();
+    trie_mut.remove(1);
+    trie_mut.remove(2);
+    assert!(trie_mut.is_empty());
+}
+
+#[test]
+fn next_empty_skips_occupied_runs_and_wraps() {
+    let trie: Trie<()> = (0..40).chain(41..50).map(|key| (key, ())).collect();
+
+    assert_eq!(trie.next_empty(0), Some(40));
+    assert_eq!(trie.next_empty(41), Some(50));
+    assert_eq!(trie.next_empty(100), Some(100));
+
+    let top = Trie::new().update(usize::MAX, ());
+    assert_eq!(top.next_empty(usize::MAX), Some(0));
+}
+
+#[test]
+fn nth_key_descends_by_cached_lengths() {
+    let keys: Vec<usize> = (0..100).map(|key| (key * 7919) | (key % 32) << (USIZE_BITS - 5)).collect();
+    let trie: Trie<()> = keys.iter().map(|&key| (key, ())).collect();
+    let mut sorted = keys.clone();
+    sorted.sort();
+
+    let root = trie.root.as_ref().unwrap();
+
+    for (n, &key) in sorted.iter().enumerate() {
+        assert_eq!(root.nth_key(n), key);
+    }
+}
+
+/// Trie with a single leaf wrapped in an inner node, which `validate` rejects
+fn uncollapsed(index: usize) -> Trie<char> {
+    let bitset = Bitset::new().with_set(Index32::convert(index, 0));
+
+    Trie {
+        root: Some(Node::more(bitset, vec![One { index, value: 'a' }].into())),
+        length: 1,
+        key: PhantomData,
+    }
+}
+
+#[test]
+fn validate_rejects_uncollapsed_and_misrouted_nodes() {
+    let mut misrouted = uncollapsed(5);
+    misrouted.root = misrouted.root.map(|node| match node {
+        More { nodes, .. } => Node::more(Bitset::new().with_set(Index32::new(3)), nodes),
+        node => node,
+    });
+
+    assert!(!uncollapsed(5).validate());
+    assert!(!misrouted.validate());
+    assert!(!Trie::<char> { root: None, length: 1, key: PhantomData }.validate());
+}
+
+#[test]
+fn validate_rejects_stale_cached_lengths() {
+    let mut trie: Trie<char> = vec![(1, 'a'), (2, 'b')].into_iter().collect();
+    assert!(trie.validate());
+
+    trie.root = trie.root.map(|node| match node {
+        More { bitset, nodes, length } => More { bitset, nodes, length: length + 1 },
+        node => node,
+    });
+    trie.length += 1;
+
+    assert!(!trie.validate());
+}
+
+#[test]
+fn rebuild_restores_the_invariants() {
+    let rebuilt = uncollapsed(5).rebuild();
+
+    assert!(rebuilt.validate());
+    assert_eq!(rebuilt.get(5), Some('a'));
+    assert!(matches!(rebuilt.root, Some(One { index: 5, .. })));
+}
+
+#[test]
+fn compact_rebuilds_invalid_tries() {
+    let compacted = uncollapsed(5).compact();
+
+    assert!(compacted.validate());
+    assert_eq!(compacted.len(), 1);
+    assert!(matches!(compacted.root, Some(One { index: 5, value: 'a' })));
+}
@@ -0,0 +1,48 @@
+extern crate elsa;
+
+use std::sync::Arc;
+
+use elsa::arc_trie::ArcTrie;
+
+#[test]
+fn get_shares_the_stored_value() {
+    let trie: ArcTrie<Vec<u8>> = (0..100).map(|key| (key * 31, vec![key as u8; 16])).collect();
+    let first = trie.get(31).unwrap();
+    let second = trie.get(31).unwrap();
+
+    assert!(Arc::ptr_eq(&first, &second));
+    assert_eq!(*first, vec![1; 16]);
+    assert_eq!(trie.get_ref(31), Some(&vec![1; 16]));
+    assert_eq!(trie.get(32), None);
+    assert_eq!(trie.len(), 100);
+}
+
+#[test]
+fn update_and_remove_keep_the_original() {
+    let trie: ArcTrie<String> = vec![(1, "a".to_string()), (2, "b".to_string())].into_iter().collect();
+    let updated = trie.update(3, "c".to_string()).remove(1);
+
+    assert_eq!(trie.iter().map(|(key, value)| (key, value.as_str())).collect::<Vec<_>>(), vec![(1, "a"), (2, "b")]);
+    assert_eq!(updated.iter().map(|(key, value)| (key, value.as_str())).collect::<Vec<_>>(), vec![(2, "b"), (3, "c")]);
+    assert!(!updated.contains_key(1));
+    assert!(Arc::ptr_eq(&trie.get(2).unwrap(), &updated.get(2).unwrap()));
+}
+
+#[test]
+fn update_arc_stores_the_given_handle() {
+    let value = Arc::new(42);
+    let trie = ArcTrie::new().update_arc(7, value.clone());
+
+    assert!(Arc::ptr_eq(&trie.get(7).unwrap(), &value));
+    assert_eq!(Arc::strong_count(&value), 2);
+    assert_eq!(trie.as_trie().len(), 1);
+}
+
+#[test]
+fn eq_compares_the_values() {
+    let trie: ArcTrie<u32> = vec![(1, 10), (2, 20)].into_iter().collect();
+
+    assert_eq!(trie, ArcTrie::new().update(2, 20).update(1, 10));
+    assert_ne!(trie, trie.update(2, 21));
+    assert!(ArcTrie::<u32>::default().is_empty());
+}
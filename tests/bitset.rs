@@ -0,0 +1,140 @@
+extern crate elsa;
+
+use elsa::bitset::{Bitset, Index32};
+
+const PATTERNS: [u32; 7] = [0, !0, 1, 1 << 31, 0xdead_beef, 0x8000_0001, 0x1234_5678];
+
+/// Bits of `bits` at the positions accepted by `keep`
+fn filtered<F: Fn(usize) -> bool>(bits: u32, keep: F) -> u32 {
+    (0..32).filter(|&pos| keep(pos) && bits >> pos & 1 == 1).map(|pos| 1 << pos).sum()
+}
+
+#[test]
+fn masks_keep_the_requested_positions() {
+    for &bits in &PATTERNS {
+        let bitset = Bitset::from(bits);
+
+        for lo in 0..32 {
+            assert_eq!(bitset.mask_below(Index32::new(lo)), filtered(bits, |pos| pos < lo));
+            assert_eq!(bitset.mask_above(Index32::new(lo)), filtered(bits, |pos| pos > lo));
+
+            for hi in lo..32 {
+                assert_eq!(bitset.mask_between(Index32::new(lo), Index32::new(hi)),
+                    filtered(bits, |pos| lo <= pos && pos <= hi));
+            }
+        }
+    }
+}
+
+#[test]
+fn mask_between_with_reversed_bounds_is_empty() {
+    assert_eq!(Bitset::from(!0).mask_between(Index32::new(9), Index32::new(8)), 0);
+}
+
+#[test]
+#[should_panic]
+fn index_out_of_range_panics() {
+    Index32::new(32);
+}
+
+#[test]
+fn first_and_last_set_positions() {
+    for &bits in &PATTERNS {
+        let bitset = Bitset::from(bits);
+
+        assert_eq!(bitset.first_set().map(|idx32| idx32.num()), (0..32).find(|pos| bits >> pos & 1 == 1));
+        assert_eq!(bitset.last_set().map(|idx32| idx32.num()), (0..32).rev().find(|pos| bits >> pos & 1 == 1));
+    }
+}
+
+#[test]
+fn toggle_flips_and_reports_the_previous_bit() {
+    for pos in 0..32 {
+        let index = Index32::new(pos);
+        let mut bitset = Bitset::new();
+
+        assert!(!bitset.toggle(index));
+        assert_eq!(bitset.num(), 1 << pos);
+        assert!(bitset.toggle(index));
+        assert_eq!(bitset, Bitset::new());
+    }
+}
+
+#[test]
+fn builders_chain_set_and_unset() {
+    let index = Index32::new;
+    let bitset = Bitset::new()
+        .with_set(index(1))
+        .with_set(index(31))
+        .with_unset(index(1))
+        .with_set(index(4))
+        .with_unset(index(9));
+
+    assert_eq!(bitset.num(), 1 << 31 | 1 << 4);
+
+    let mut unset = bitset;
+    assert!(unset.unset(index(31)));
+    assert!(!unset.unset(index(31)));
+    assert_eq!(unset.num(), 1 << 4);
+}
+
+#[test]
+fn u32_conversions_round_trip() {
+    for &bits in &PATTERNS {
+        let bitset = Bitset::from(bits);
+
+        assert_eq!(u32::from(bitset), bits);
+        assert_eq!(bitset, bits);
+        assert_eq!(bitset.num(), bits);
+        assert_eq!(bitset.iter().count(), bits.count_ones() as usize);
+    }
+
+    let bitset = Bitset::from(0b1010);
+    assert!(bitset.get(Index32::new(1)) && !bitset.get(Index32::new(2)));
+    assert!(bitset != 0b1011);
+}
+
+#[test]
+fn difference_of_random_pairs() {
+    let mut state = 0x9e37_79b9u32;
+    let mut next = || {
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+        state
+    };
+
+    for _ in 0..1000 {
+        let (a, b) = (next(), next());
+        let expected: Vec<_> = (0..32).filter(|&pos| a >> pos & 1 == 1 && b >> pos & 1 == 0).collect();
+
+        assert_eq!(Bitset::from(a).only_in(Bitset::from(b)), a & !b);
+        assert_eq!(Bitset::from(a).difference_iter(Bitset::from(b)).map(|idx32| idx32.num()).collect::<Vec<_>>(), expected);
+    }
+}
+
+#[test]
+fn difference_with_itself_and_empty() {
+    for &bits in &PATTERNS {
+        let bitset = Bitset::from(bits);
+
+        assert_eq!(bitset.difference_iter(bitset).next(), None);
+        assert_eq!(bitset.only_in(Bitset::new()), bitset);
+        assert_eq!(Bitset::new().only_in(bitset), 0);
+    }
+}
+
+#[test]
+fn count_below_matches_a_brute_force_count() {
+    for &bits in &PATTERNS {
+        let bitset = Bitset::from(bits);
+
+        for pos in 0..32 {
+            let expected = (0..pos).filter(|&below| bits >> below & 1 == 1).count();
+            assert_eq!(bitset.count_below(Index32::new(pos)), expected);
+
+            let packed = if bits >> pos & 1 == 1 { Some(expected) } else { None };
+            assert_eq!(bitset.packed_index(Index32::new(pos)), packed);
+        }
+    }
+}
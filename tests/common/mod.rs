@@ -0,0 +1,51 @@
+//! Helpers shared by the integration tests
+
+#![allow(dead_code)]
+
+use std::collections::BTreeMap;
+
+use elsa::trie::Trie;
+
+/// Xorshift generator, so the random tests are reproducible
+pub struct Rng(pub u64);
+
+impl Rng {
+    pub fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// Key drawn from small, mid-range, large and near `usize::MAX` values
+    pub fn key(&mut self) -> usize {
+        let r = self.next();
+
+        match r % 4 {
+            0 => (r >> 8) as usize,
+            1 => (r >> 40) as usize,
+            2 => ((r >> 20) % 200) as usize,
+            _ => usize::MAX - ((r >> 20) % 100) as usize,
+        }
+    }
+}
+
+/// Trie of `n` random updates, and a `BTreeMap` which received the same ones
+pub fn sample(seed: u64, n: usize) -> (Trie<u64>, BTreeMap<usize, u64>) {
+    let mut rng = Rng(seed);
+    let mut trie = Trie::new();
+    let mut reference = BTreeMap::new();
+
+    for value in 0..n as u64 {
+        let key = rng.key();
+        trie = trie.update(key, value);
+        reference.insert(key, value);
+    }
+
+    (trie, reference)
+}
+
+/// Entries of `trie` with their values copied out
+pub fn entries<T: Clone>(trie: &Trie<T>) -> Vec<(usize, T)> {
+    trie.iter().map(|(index, value)| (index, value.clone())).collect()
+}
@@ -0,0 +1,127 @@
+extern crate elsa;
+
+use elsa::keyed::{KeyedTrie, TrieKey};
+use elsa::trie::Trie;
+
+fn u16_trie(keys: &[u16]) -> KeyedTrie<u16, u16> {
+    keys.iter().map(|&key| (key, key)).collect()
+}
+
+#[test]
+fn max_depth_follows_the_key_width() {
+    assert_eq!(<u8 as TrieKey>::max_depth(), 2);
+    assert_eq!(<u16 as TrieKey>::max_depth(), 4);
+    assert_eq!(<u32 as TrieKey>::max_depth(), 7);
+    assert_eq!(<usize as TrieKey>::max_depth(), (usize::BITS as usize - 1) / 5 + 1);
+}
+
+#[test]
+fn u16_keys_at_the_boundaries() {
+    let trie = u16_trie(&[0, 1, u16::MAX - 1, u16::MAX]);
+
+    assert!(trie.validate());
+    assert_eq!(trie.len(), 4);
+    assert_eq!(trie.get(0), Some(0));
+    assert_eq!(trie.get(u16::MAX), Some(u16::MAX));
+    assert_eq!(trie.get(2), None);
+    assert_eq!(trie.min_key(), Some(0));
+    assert_eq!(trie.max_key(), Some(u16::MAX));
+    assert_eq!(trie.iter().map(|(key, _)| key).collect::<Vec<_>>(), vec![0, 1, u16::MAX - 1, u16::MAX]);
+}
+
+#[test]
+fn u16_tries_stay_within_their_depth() {
+    let every: KeyedTrie<u16, ()> = (0..=u16::MAX).map(|key| (key, ())).collect();
+    let histogram = every.depth_histogram();
+
+    assert_eq!(every.len(), 1 << 16);
+    assert!(histogram.len() <= <u16 as TrieKey>::max_depth() + 1);
+    assert_eq!(histogram.iter().sum::<usize>(), 1 << 16);
+
+    let bytes: KeyedTrie<u8, ()> = (0..=u8::MAX).map(|key| (key, ())).collect();
+    assert_eq!(bytes.depth_histogram(), vec![0, 0, 256]);
+}
+
+#[test]
+fn u16_range_and_removal() {
+    let trie: KeyedTrie<u16, u32> = (0..=u16::MAX).step_by(7).map(|key| (key, key as u32)).collect();
+
+    assert_eq!(trie.range(..14).map(|(key, _)| key).collect::<Vec<_>>(), vec![0, 7]);
+    assert_eq!(trie.range(u16::MAX - 10..).map(|(key, _)| key).collect::<Vec<_>>(), vec![65527, 65534]);
+    assert_eq!(trie.range(1..=6).next(), None);
+
+    let removed = trie.remove_all((0..=u16::MAX).step_by(14));
+    assert!(removed.validate());
+    assert_eq!(removed.len(), trie.len() / 2);
+    assert!(removed.iter().all(|(key, _)| key % 14 == 7));
+    assert_eq!(trie.remove(7).update(8, 0).get(8), Some(0));
+}
+
+#[test]
+fn u16_iter_mut_and_into_iter() {
+    let trie = u16_trie(&[3, 300, u16::MAX]);
+    let mut iter_mut = trie.iter_mut();
+
+    for (key, value) in &mut iter_mut {
+        *value = key / 3;
+    }
+
+    let updated = iter_mut.into_trie();
+    assert_eq!(updated.into_iter().collect::<Vec<_>>(), vec![(3, 1), (300, 100), (u16::MAX, 21845)]);
+}
+
+#[test]
+fn u16_gaps_and_free_runs_step_by_key() {
+    let trie = u16_trie(&[0, 1, 5, u16::MAX]);
+
+    assert_eq!(trie.gaps(..).collect::<Vec<_>>(), vec![2..5, 6..u16::MAX]);
+    assert_eq!(trie.gaps(0..=3).collect::<Vec<_>>(), vec![2..4]);
+    assert_eq!(trie.first_free_run(0, 3), Some(2));
+    assert_eq!(trie.first_free_run(0, 4), Some(6));
+    assert_eq!(trie.next_empty(0), Some(2));
+    assert_eq!(trie.next_empty(u16::MAX), Some(2));
+    assert_eq!(trie.occupied_ranges(), vec![0..=1, 5..=5, u16::MAX..=u16::MAX]);
+    assert_eq!(trie.segments(|key, _| key > 1), vec![5..u16::MAX]);
+    assert_eq!(trie.remove_and_next(1), (u16_trie(&[0, 5, u16::MAX]), Some(5)));
+    assert_eq!(trie.floor(4), Some((1, &1)));
+    assert_eq!(trie.ceil(6), Some((u16::MAX, &u16::MAX)));
+}
+
+#[test]
+fn u16_dense_runs_and_fills() {
+    let dense = u16_trie(&[0, 1, 2, 3]);
+    let filled = KeyedTrie::new().fill_range(u16::MAX - 2, 3, 7u16);
+
+    assert_eq!(dense.as_dense_vec(), Some(vec![0, 1, 2, 3]));
+    assert_eq!(u16_trie(&[0, 2]).as_dense_vec(), None);
+    assert!(filled.validate());
+    assert_eq!(filled.iter().map(|(key, _)| key).collect::<Vec<_>>(), vec![u16::MAX - 2, u16::MAX - 1, u16::MAX]);
+    assert_eq!(filled.run_length_encode(), vec![(u16::MAX - 2, 3, 7)]);
+    assert_eq!(KeyedTrie::from_run_length(filled.run_length_encode()), filled);
+    assert_eq!(dense.densify_range(2, 6).len(), 6);
+}
+
+#[test]
+fn u16_prefixes_and_bits() {
+    let trie = u16_trie(&[0x0001, 0x0800, 0x0801, 0xf000]);
+    let allowed = [0x0800, 0xf000].iter().cloned().collect();
+
+    assert_eq!(trie.max_key_bits(), 16);
+    assert_eq!(u16_trie(&[5]).max_key_bits(), 3);
+    assert_eq!(trie.subtree(0x0000, 5).len(), 1);
+    assert_eq!(trie.subtree(0x0800, 5).len(), 2);
+    assert_eq!(trie.subtree(0x0801, 15).len(), 2);
+    assert_eq!(trie.prefix_count(0xf000, 15), 1);
+    assert_eq!(trie.retain_matching(&allowed), u16_trie(&[0x0800, 0xf000]));
+    assert_eq!(trie.retain_in_ranges(&[0..0x0002, 0x0801..0xf001]), u16_trie(&[0x0001, 0x0801, 0xf000]));
+    assert_eq!(trie.key_range(), Some((0x0001, 0xf000)));
+}
+
+#[test]
+fn usize_tries_are_unchanged() {
+    let trie: Trie<u8> = Trie::new().update(usize::MAX, 1).update(0, 2);
+    let same: KeyedTrie<usize, u8> = trie.clone();
+
+    assert_eq!(trie, same);
+    assert_eq!(trie.iter().next(), Some((0, &2)));
+}
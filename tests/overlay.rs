@@ -0,0 +1,51 @@
+extern crate elsa;
+
+mod common;
+
+use std::collections::BTreeMap;
+
+use elsa::overlay::Overlay;
+use elsa::trie::Trie;
+
+use common::sample;
+
+#[test]
+fn overlay_shadows_the_base() {
+    let base: Trie<&str> = vec![(1, "b1"), (2, "b2"), (100, "b100")].into_iter().collect();
+    let top: Trie<&str> = vec![(2, "o2"), (3, "o3"), (usize::MAX, "max")].into_iter().collect();
+    let overlay = base.overlay(&top);
+
+    assert_eq!(overlay.get(1), Some("b1"));
+    assert_eq!(overlay.get(2), Some("o2"));
+    assert_eq!(overlay.get(3), Some("o3"));
+    assert_eq!(overlay.get(4), None);
+    assert!(overlay.contains_key(100) && !overlay.contains_key(5));
+
+    let visible: Vec<_> = overlay.iter().map(|(key, &value)| (key, value)).collect();
+    assert_eq!(visible, vec![(1, "b1"), (2, "o2"), (3, "o3"), (100, "b100"), (usize::MAX, "max")]);
+}
+
+#[test]
+fn overlay_iter_matches_a_merged_map() {
+    for seed in 1..10 {
+        let (base, mut merged) = sample(seed, 300);
+        let (top, top_reference) = sample(seed * 1000, 300);
+        merged.extend(top_reference);
+
+        let overlay = Overlay::new(&base, &top);
+        let visible: BTreeMap<_, _> = overlay.iter().map(|(key, &value)| (key, value)).collect();
+
+        assert_eq!(overlay.iter().count(), merged.len());
+        assert_eq!(visible, merged);
+    }
+}
+
+#[test]
+fn overlay_of_empty_tries() {
+    let empty = Trie::<u8>::new();
+    let single = Trie::new().update(7, 1);
+
+    assert_eq!(empty.overlay(&empty).iter().next(), None);
+    assert_eq!(empty.overlay(&single).get(7), Some(1));
+    assert_eq!(single.overlay(&empty).iter().collect::<Vec<_>>(), vec![(7, &1)]);
+}
@@ -0,0 +1,45 @@
+#![cfg(feature = "parallel")]
+
+extern crate elsa;
+
+mod common;
+
+use elsa::trie::Trie;
+
+use common::{Rng, sample, entries};
+
+#[test]
+fn from_sorted_parallel_matches_from_sorted_slice() {
+    for seed in 0..10 {
+        let (_, reference) = sample(169 + seed, 2000);
+        let sorted: Vec<_> = reference.into_iter().collect();
+        let parallel = Trie::from_sorted_parallel(&sorted);
+
+        assert!(parallel.validate());
+        assert_eq!(parallel.len(), sorted.len());
+        assert_eq!(parallel, Trie::from_sorted_slice(&sorted));
+    }
+}
+
+#[test]
+fn from_sorted_parallel_of_uniform_keys() {
+    let mut rng = Rng(169);
+    let mut keys: Vec<_> = (0..5000).map(|_| rng.next() as usize).collect();
+    keys.sort();
+    keys.dedup();
+    let sorted: Vec<_> = keys.into_iter().map(|key| (key, key)).collect();
+    let parallel = Trie::from_sorted_parallel(&sorted);
+
+    assert!(parallel.validate());
+    assert_eq!(entries(&parallel), sorted);
+}
+
+#[test]
+fn from_sorted_parallel_of_few_entries() {
+    let clustered: Vec<_> = (0..40).map(|key| (key, key)).collect();
+
+    assert!(Trie::<usize>::from_sorted_parallel(&[]).is_empty());
+    assert_eq!(Trie::from_sorted_parallel(&[(7, 7)]), Trie::from_sorted_slice(&[(7, 7)]));
+    assert_eq!(Trie::from_sorted_parallel(&clustered), Trie::from_sorted_slice(&clustered));
+    assert!(Trie::from_sorted_parallel(&clustered).validate());
+}
@@ -0,0 +1,2423 @@
+extern crate elsa;
+
+mod common;
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use elsa::bitset::{Bitset, Index32};
+use elsa::trie::{AliasError, Change, MerkleCache, Trie, TrieMut, UpdateKind, bloom_may_contain};
+
+use common::{Rng, sample, entries};
+
+fn trie_of(keys: &[usize]) -> Trie<usize> {
+    keys.iter().map(|&key| (key, key)).collect()
+}
+
+#[test]
+fn depth_histogram_of_clustered_keys() {
+    let trie = trie_of(&(0..32).collect::<Vec<_>>());
+    let histogram = trie.depth_histogram();
+
+    assert_eq!(histogram.iter().sum::<usize>(), 32);
+    assert_eq!(histogram.last(), Some(&32));
+    assert!(histogram[..histogram.len() - 1].iter().all(|&count| count == 0));
+}
+
+#[test]
+fn depth_histogram_of_spread_keys() {
+    let spread: Vec<_> = (0..16).map(|top| top << (usize::BITS - 4)).collect();
+    let trie = trie_of(&spread);
+
+    assert_eq!(trie.depth_histogram(), vec![0, 16]);
+}
+
+#[test]
+fn depth_histogram_of_empty_and_single_tries() {
+    assert!(Trie::<()>::new().depth_histogram().is_empty());
+    assert_eq!(trie_of(&[42]).depth_histogram(), vec![1]);
+}
+
+/// Keys which share the high `prefix_bits` bits of `prefix`, as an inclusive range
+fn prefix_range(prefix: usize, prefix_bits: usize) -> (usize, usize) {
+    let low = usize::MAX.checked_shr(prefix_bits as u32).unwrap_or(0);
+
+    (prefix & !low, prefix | low)
+}
+
+#[test]
+fn subtree_matches_the_prefix_range() {
+    let (trie, reference) = sample(99, 2000);
+    let probes: Vec<usize> = reference.keys().cloned().step_by(37)
+        .chain(vec![0, 12345, usize::MAX])
+        .collect();
+
+    for prefix_bits in (0..usize::BITS as usize).step_by(5) {
+        for &prefix in &probes {
+            let (lo, hi) = prefix_range(prefix, prefix_bits);
+            let expected: Vec<_> = reference.range(lo..=hi).map(|(&k, &v)| (k, v)).collect();
+            let subtree = trie.subtree(prefix, prefix_bits);
+
+            assert!(subtree.validate());
+            assert_eq!(entries(&subtree), expected);
+        }
+    }
+}
+
+#[test]
+fn subtree_of_missing_prefix_is_empty() {
+    let trie = trie_of(&[1, 2, 3]);
+
+    assert!(trie.subtree(usize::MAX, 5).is_empty());
+    assert!(Trie::<()>::new().subtree(0, 0).is_empty());
+    assert_eq!(trie.subtree(0, 0), trie);
+}
+
+#[test]
+#[should_panic]
+fn subtree_rejects_unaligned_prefix() {
+    trie_of(&[1]).subtree(0, 3);
+}
+
+#[test]
+fn iter_mut_updates_a_copy() {
+    for seed in 1..10 {
+        let (trie, reference) = sample(seed * 31, 500);
+        let mut iter_mut = trie.iter_mut();
+
+        for (key, value) in &mut iter_mut {
+            *value += key as u64 % 7;
+        }
+
+        let updated = iter_mut.into_trie();
+
+        for (&key, &value) in &reference {
+            assert_eq!(updated.get(key), Some(value + key as u64 % 7));
+            assert_eq!(trie.get(key), Some(value));
+        }
+        assert!(updated.validate());
+    }
+}
+
+#[test]
+fn iter_mut_visits_keys_in_order() {
+    let (trie, reference) = sample(7, 300);
+    let mut iter_mut = trie.iter_mut();
+    let keys: Vec<_> = (&mut iter_mut).into_iter().map(|(key, _)| key).collect();
+
+    assert_eq!(keys, reference.keys().cloned().collect::<Vec<_>>());
+}
+
+#[test]
+fn iter_mut_of_empty_and_single_tries() {
+    let mut empty = Trie::<u8>::new().iter_mut();
+    assert_eq!((&mut empty).into_iter().count(), 0);
+    assert!(empty.into_trie().is_empty());
+
+    let mut single = Trie::new().update(3, 3u8).iter_mut();
+    for (_, value) in &mut single {
+        *value = 9;
+    }
+    assert_eq!(single.into_trie().get(3), Some(9));
+}
+
+#[test]
+fn dedup_values_shares_equal_values() {
+    use std::sync::Arc;
+
+    let trie: Trie<Arc<String>> = (0..100)
+        .map(|key| (key * 1000, Arc::new((key % 3).to_string())))
+        .collect();
+    let deduped = trie.dedup_values();
+
+    assert!(Arc::ptr_eq(&deduped.get(0).unwrap(), &deduped.get(3000).unwrap()));
+    assert!(!Arc::ptr_eq(&trie.get(0).unwrap(), &trie.get(3000).unwrap()));
+    assert_eq!(deduped, trie);
+}
+
+#[test]
+fn get_mut_or_insert_with_existing_keys() {
+    let (trie, reference) = sample(5, 400);
+    let mut trie_mut = trie.to_mut();
+
+    for &key in reference.keys() {
+        *trie_mut.get_mut_or_insert_with(key, || panic!("{} exists", key)) += 1;
+    }
+
+    let trie = trie_mut.into_trie();
+    assert_eq!(trie.len(), reference.len());
+    for (&key, &value) in &reference {
+        assert_eq!(trie.get(key), Some(value + 1));
+    }
+}
+
+#[test]
+fn get_mut_or_insert_with_absent_keys() {
+    let (trie, reference) = sample(5, 400);
+    let mut trie_mut = trie.to_mut();
+    let absent: Vec<_> = (0..200).map(|key| key * 7 + 1).filter(|key| !reference.contains_key(key)).collect();
+
+    for &key in &absent {
+        let mut called = false;
+        *trie_mut.get_mut_or_insert_with(key, || { called = true; 5000 }) += 1;
+        assert!(called);
+    }
+
+    let trie = trie_mut.into_trie();
+    assert!(trie.validate());
+    assert_eq!(trie.len(), reference.len() + absent.len());
+    for &key in &absent {
+        assert_eq!(trie.get(key), Some(5001));
+    }
+}
+
+#[test]
+fn eq_of_clones_skips_value_comparisons() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static COMPARED: AtomicUsize = AtomicUsize::new(0);
+
+    #[derive(Debug, Clone)]
+    struct Counted(usize);
+
+    impl PartialEq for Counted {
+        fn eq(&self, other: &Counted) -> bool {
+            COMPARED.fetch_add(1, Ordering::SeqCst);
+            self.0 == other.0
+        }
+    }
+
+    let trie: Trie<Counted> = (0..1000).map(|key| (key * 7919, Counted(key))).collect();
+
+    assert!(trie == trie.clone());
+    assert_eq!(COMPARED.load(Ordering::SeqCst), 0);
+
+    let updated = trie.update(7919 * 5, Counted(1));
+    assert!(trie != updated);
+    assert!(COMPARED.load(Ordering::SeqCst) < 50);
+
+    let rebuilt: Trie<Counted> = trie.iter().map(|(key, value)| (key, value.clone())).collect();
+    assert!(rebuilt == trie);
+    assert!(COMPARED.load(Ordering::SeqCst) >= 1000);
+}
+
+#[test]
+fn eq_compares_entries_of_distinct_roots() {
+    let (left, _) = sample(3, 300);
+    let (right, _) = sample(3, 300);
+    let first = left.min_key().unwrap();
+
+    assert_eq!(left, right);
+    assert_ne!(left, left.remove(first));
+    assert_ne!(left, right.update(first, u64::MAX));
+}
+
+#[test]
+fn from_sorted_slice_matches_insertion() {
+    for seed in 1..8 {
+        let (trie, reference) = sample(seed * 13, 800);
+        let sorted: Vec<_> = reference.iter().map(|(&k, &v)| (k, v)).collect();
+        let built = Trie::from_sorted_slice(&sorted);
+
+        assert!(built.validate());
+        assert_eq!(built.len(), trie.len());
+        assert_eq!(built, trie);
+        assert_eq!(built.depth_histogram(), trie.depth_histogram());
+    }
+}
+
+#[test]
+fn from_sorted_slice_of_few_entries() {
+    assert!(Trie::<u8>::from_sorted_slice(&[]).is_empty());
+    assert_eq!(Trie::from_sorted_slice(&[(usize::MAX, 1)]).get(usize::MAX), Some(1));
+    assert_eq!(Trie::from_sorted_slice(&[(0, 'a'), (usize::MAX, 'b')]).len(), 2);
+}
+
+#[test]
+fn min_and_max_keys() {
+    for seed in 1..20 {
+        let (trie, reference) = sample(seed * 17, 1 + seed as usize * 20);
+
+        assert_eq!(trie.min_key(), reference.keys().next().cloned());
+        assert_eq!(trie.max_key(), reference.keys().next_back().cloned());
+    }
+
+    assert_eq!(Trie::<u8>::new().min_key(), None);
+    assert_eq!(Trie::<u8>::new().max_key(), None);
+    assert_eq!(trie_of(&[9]).min_key(), Some(9));
+    assert_eq!(trie_of(&[9]).max_key(), Some(9));
+}
+
+#[test]
+fn select_keys_keeps_present_keys_once() {
+    let trie: Trie<usize> = (0..5000).map(|key| (key, key * 2)).collect();
+    let selected = trie.select_keys(vec![3, 10, 4999, 6000, 3]);
+
+    assert!(selected.validate());
+    assert_eq!(entries(&selected), vec![(3, 6), (10, 20), (4999, 9998)]);
+    assert!(trie.select_keys(vec![]).is_empty());
+    assert!(Trie::<u8>::new().select_keys(vec![1, 2]).is_empty());
+}
+
+#[test]
+fn reserve_path_keeps_the_contents() {
+    let (trie, reference) = sample(8, 500);
+    let mut trie_mut = trie.to_mut();
+
+    for &key in reference.keys().step_by(5) {
+        trie_mut.reserve_path(key);
+        trie_mut.reserve_path(key ^ 0xffff);
+    }
+
+    assert_eq!(trie_mut.len(), reference.len());
+    assert_eq!(trie_mut.into_trie(), trie);
+    assert_eq!(trie, sample(8, 500).0);
+}
+
+#[test]
+fn reserved_paths_accept_insertions() {
+    let (trie, mut reference) = sample(8, 500);
+    let mut trie_mut = trie.to_mut();
+    trie_mut.reserve_path(1 << 20);
+
+    for key in 0..100 {
+        trie_mut.insert((1 << 20) + key, 7);
+        reference.insert((1 << 20) + key, 7);
+    }
+
+    let trie = trie_mut.into_trie();
+    assert!(trie.validate());
+    assert_eq!(entries(&trie), reference.into_iter().collect::<Vec<_>>());
+
+    let mut empty = Trie::<u8>::new().to_mut();
+    empty.reserve_path(123);
+    assert!(empty.into_trie().is_empty());
+}
+
+#[test]
+fn reduce_values_folds_in_key_order() {
+    let (trie, reference) = sample(4, 700);
+    let joined: Trie<String> = trie.iter().map(|(key, value)| (key, value.to_string())).collect();
+    let expected: Vec<_> = reference.values().map(|value| value.to_string()).collect();
+
+    assert_eq!(trie.reduce_values(|a, b| a + b), Some(reference.values().sum()));
+    assert_eq!(joined.reduce_values(|a, b| format!("{},{}", a, b)), Some(expected.join(",")));
+    assert_eq!(trie_of(&[5]).reduce_values(|_, _| unreachable!()), Some(5));
+    assert_eq!(Trie::<u8>::new().reduce_values(|a, _| *a), None);
+}
+
+#[test]
+fn key_range_matches_min_and_max_keys() {
+    for seed in 1..20 {
+        let (trie, reference) = sample(seed * 19, seed as usize * 10);
+        let expected = reference.keys().next().map(|&min| (min, *reference.keys().next_back().unwrap()));
+
+        assert_eq!(trie.key_range(), expected);
+    }
+
+    assert_eq!(trie_of(&[5, 6]).key_range(), Some((5, 6)));
+    assert_eq!(trie_of(&[0, usize::MAX]).key_range(), Some((0, usize::MAX)));
+    assert_eq!(trie_of(&[9]).key_range(), Some((9, 9)));
+    assert_eq!(Trie::<u8>::new().key_range(), None);
+}
+
+#[test]
+fn contains_all_and_any() {
+    let trie: Trie<u8> = (0..100).map(|key| (key * 3, 0)).collect();
+
+    assert!(trie.contains_all(vec![0, 3, 297]));
+    assert!(!trie.contains_all(vec![0, 1]));
+    assert!(trie.contains_any(vec![1, 2, 3]));
+    assert!(!trie.contains_any(vec![1, 2, 4]));
+    assert!(trie.contains_all(vec![]));
+    assert!(!trie.contains_any(vec![]));
+}
+
+#[test]
+fn contains_all_and_any_stop_early() {
+    let trie: Trie<u8> = (0..100).map(|key| (key * 3, 0)).collect();
+    let mut visited = 0;
+
+    assert!(!trie.contains_all((0..100).inspect(|_| visited += 1)));
+    assert_eq!(visited, 2);
+
+    visited = 0;
+    assert!(trie.contains_any((1..100).inspect(|_| visited += 1)));
+    assert_eq!(visited, 3);
+}
+
+#[test]
+fn removals_keep_the_trie_valid() {
+    for seed in 1..30 {
+        let (mut trie, reference) = sample(seed * 23, 300);
+        assert!(trie.validate());
+
+        for &key in reference.keys().step_by(2) {
+            trie = trie.remove(key);
+            assert!(trie.validate());
+        }
+
+        assert_eq!(trie.rebuild(), trie);
+        assert!(trie.rebuild().shares_root(&trie));
+    }
+
+    assert!(Trie::<u8>::new().validate());
+}
+
+#[test]
+fn split_retain_moves_unshared_values() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static CLONED: AtomicUsize = AtomicUsize::new(0);
+
+    #[derive(Debug, PartialEq)]
+    struct Counted(u64);
+
+    impl Clone for Counted {
+        fn clone(&self) -> Counted {
+            CLONED.fetch_add(1, Ordering::SeqCst);
+            Counted(self.0)
+        }
+    }
+
+    let (_, reference) = sample(41, 600);
+    let owned: Vec<_> = reference.iter().map(|(&k, &v)| (k, Counted(v))).collect();
+    let trie = Trie::from_sorted_slice(&owned);
+    let before = CLONED.load(Ordering::SeqCst);
+    let (kept, removed) = trie.split_retain(|key, value| (key as u64).wrapping_add(value.0) % 3 == 0);
+
+    assert_eq!(CLONED.load(Ordering::SeqCst), before);
+    assert!(kept.validate() && removed.validate());
+    assert_eq!(kept.len() + removed.len(), reference.len());
+
+    for (&key, &value) in &reference {
+        let side = if (key as u64).wrapping_add(value) % 3 == 0 { &kept } else { &removed };
+        assert_eq!(side.get_ref(key), Some(&Counted(value)));
+    }
+}
+
+#[test]
+fn split_retain_leaves_shared_tries_intact() {
+    let (trie, reference) = sample(17, 400);
+    let (even, odd) = trie.clone().split_retain(|key, _| key % 2 == 0);
+
+    assert!(even.validate() && odd.validate());
+    assert!(even.iter().all(|(key, _)| key % 2 == 0));
+    assert!(odd.iter().all(|(key, _)| key % 2 == 1));
+    assert_eq!(entries(&trie), reference.into_iter().collect::<Vec<_>>());
+    assert_eq!(Trie::<u8>::new().split_retain(|_, _| true), (Trie::new(), Trie::new()));
+}
+
+#[test]
+fn get_or_default_of_present_and_absent_keys() {
+    let trie = Trie::new().update(4, 9u32);
+
+    assert_eq!(trie.get_or_default(4), 9);
+    assert_eq!(trie.get_or_default(5), 0);
+    assert_eq!(Trie::<String>::new().get_or_default(0), "");
+}
+
+#[test]
+fn remove_and_next_walks_every_key() {
+    for seed in 1..10 {
+        let (mut trie, reference) = sample(seed * 29, 400);
+        let mut cursor = trie.min_key();
+        let mut visited = Vec::new();
+
+        while let Some(key) = cursor {
+            visited.push(key);
+            let (rest, next) = trie.remove_and_next(key);
+            trie = rest;
+            cursor = next;
+        }
+
+        assert!(trie.is_empty());
+        assert_eq!(visited, reference.keys().cloned().collect::<Vec<_>>());
+    }
+}
+
+#[test]
+fn remove_and_next_of_absent_and_last_keys() {
+    let trie = trie_of(&[3, 8, usize::MAX]);
+
+    assert_eq!(trie.remove_and_next(5), (trie.clone(), Some(8)));
+    assert_eq!(trie.remove_and_next(usize::MAX), (trie_of(&[3, 8]), None));
+    assert_eq!(Trie::<u8>::new().remove_and_next(0), (Trie::new(), None));
+}
+
+#[test]
+fn try_map_converts_every_value() {
+    let (trie, reference) = sample(51, 300);
+    let mapped: Trie<String> = trie.try_map(|_, value| Ok::<_, ()>(value.to_string())).unwrap();
+
+    assert!(mapped.validate());
+    assert_eq!(mapped.len(), reference.len());
+    for (&key, value) in &reference {
+        assert_eq!(mapped.get(key), Some(value.to_string()));
+    }
+}
+
+#[test]
+fn try_map_stops_at_the_first_error() {
+    let (trie, reference) = sample(51, 300);
+    let bad = *reference.keys().nth(100).unwrap();
+    let mut calls = 0;
+    let mapped: Result<Trie<u64>, usize> = trie.try_map(|key, &value| {
+        calls += 1;
+        if key == bad { Err(key) } else { Ok(value) }
+    });
+
+    assert_eq!(mapped, Err(bad));
+    assert_eq!(calls, 101);
+}
+
+#[test]
+fn count_where_matches_filtered_count() {
+    let (trie, reference) = sample(61, 500);
+
+    assert_eq!(trie.count_where(|_, value| value % 2 == 0), reference.values().filter(|&&value| value % 2 == 0).count());
+    assert_eq!(trie.count_where(|key, _| key < 200), reference.range(..200).count());
+    assert_eq!(trie.count_where(|_, _| true), trie.len());
+    assert_eq!(Trie::<u8>::new().count_where(|_, _| true), 0);
+}
+
+#[test]
+fn any_and_all_short_circuit() {
+    let trie = trie_of(&(0..1000).collect::<Vec<_>>());
+    let mut visited = Vec::new();
+
+    assert!(trie.any(|key, _| { visited.push(key); key == 10 }));
+    assert_eq!(visited, (0..=10).collect::<Vec<_>>());
+
+    visited.clear();
+    assert!(!trie.all(|key, _| { visited.push(key); key < 10 }));
+    assert_eq!(visited, (0..=10).collect::<Vec<_>>());
+
+    assert!(trie.all(|_, &value| value < 1000));
+    assert!(!trie.any(|_, &value| value >= 1000));
+}
+
+#[test]
+fn any_and_all_of_empty_trie() {
+    assert!(Trie::<u8>::new().all(|_, _| false));
+    assert!(!Trie::<u8>::new().any(|_, _| true));
+}
+
+#[test]
+fn range_matches_a_btreemap() {
+    for seed in 1..30 {
+        let (trie, reference) = sample(seed, 300);
+        let mut rng = Rng(seed * 31 + 5);
+
+        for _ in 0..60 {
+            let (a, b) = (rng.key(), rng.key());
+            let (lo, hi) = (a.min(b), a.max(b));
+            let keys = |iter: elsa::trie::Range<usize, u64>| iter.map(|(key, _)| key).collect::<Vec<_>>();
+
+            assert_eq!(keys(trie.range(lo..=hi)), reference.range(lo..=hi).map(|(&k, _)| k).collect::<Vec<_>>());
+            assert_eq!(keys(trie.range(lo..hi)), reference.range(lo..hi).map(|(&k, _)| k).collect::<Vec<_>>());
+            assert_eq!(keys(trie.range(lo..)), reference.range(lo..).map(|(&k, _)| k).collect::<Vec<_>>());
+            assert_eq!(keys(trie.range(..hi)), reference.range(..hi).map(|(&k, _)| k).collect::<Vec<_>>());
+        }
+    }
+}
+
+#[test]
+fn range_with_empty_bounds() {
+    let trie = trie_of(&[0, 5, usize::MAX]);
+
+    assert_eq!(trie.range(5..5).next(), None);
+    assert_eq!(trie.range(1..=4).next(), None);
+    assert_eq!(trie.range(usize::MAX..).collect::<Vec<_>>(), vec![(usize::MAX, &usize::MAX)]);
+    assert_eq!(trie.range(..).count(), 3);
+}
+
+/// Runs of keys within `start..end` absent from `trie`, by checking each key
+fn gaps_by_scan(trie: &Trie<u8>, start: usize, end: usize) -> Vec<std::ops::Range<usize>> {
+    let mut gaps = Vec::new();
+    let mut run = None;
+
+    for key in start..end {
+        match (trie.contains_key(key), run) {
+            (true, Some(begin)) => {
+                gaps.push(begin..key);
+                run = None;
+            }
+            (false, None) => run = Some(key),
+            _ => {}
+        }
+    }
+
+    gaps.extend(run.map(|begin| begin..end));
+    gaps
+}
+
+#[test]
+fn gaps_are_the_holes_between_keys() {
+    let trie: Trie<u8> = [(3, 0), (4, 0), (9, 0), (20, 0)].iter().cloned().collect();
+
+    for start in 0..25 {
+        for end in start..25 {
+            assert_eq!(trie.gaps(start..end).collect::<Vec<_>>(), gaps_by_scan(&trie, start, end));
+        }
+    }
+
+    assert_eq!(trie.gaps(..).collect::<Vec<_>>(), vec![0..3, 5..9, 10..20, 21..usize::MAX]);
+    assert_eq!(Trie::<u8>::new().gaps(5..=7).collect::<Vec<_>>(), vec![5..8]);
+}
+
+#[test]
+fn gaps_near_usize_max() {
+    let trie: Trie<u8> = [(usize::MAX, 0), (usize::MAX - 2, 0)].iter().cloned().collect();
+
+    assert_eq!(trie.gaps(usize::MAX - 4..).collect::<Vec<_>>(),
+        vec![usize::MAX - 4..usize::MAX - 2, usize::MAX - 1..usize::MAX]);
+}
+
+#[test]
+fn batch_applies_operations_in_order() {
+    use elsa::trie::Op;
+
+    for seed in 1..20 {
+        let (trie, mut reference) = sample(seed, 200);
+        let mut rng = Rng(seed + 99);
+        let mut ops = Vec::new();
+
+        for value in 0..300 {
+            let key = if rng.next().is_multiple_of(2) { (rng.next() % 50) as usize } else { rng.key() };
+
+            if rng.next().is_multiple_of(3) {
+                ops.push(Op::Remove(key));
+                reference.remove(&key);
+            } else {
+                ops.push(Op::Insert(key, value));
+                reference.insert(key, value);
+            }
+        }
+
+        let applied = trie.batch(ops);
+        assert!(applied.validate());
+        assert_eq!(entries(&applied), reference.into_iter().collect::<Vec<_>>());
+    }
+}
+
+#[test]
+fn batch_of_no_operations_keeps_the_trie() {
+    use elsa::trie::Op;
+
+    let trie = trie_of(&[1, 2]);
+
+    assert!(trie.batch(vec![]).shares_root(&trie));
+    assert_eq!(trie.batch(vec![Op::Insert(3, 3), Op::Remove(3)]), trie);
+}
+
+#[test]
+fn floor_and_ceil_match_a_btreemap() {
+    for seed in 1..30 {
+        let (trie, reference) = sample(seed, 300);
+        let mut rng = Rng(seed * 3 + 1);
+        let queries: Vec<_> = (0..200).map(|_| rng.key())
+            .chain(reference.keys().cloned())
+            .chain(vec![0, usize::MAX])
+            .collect();
+
+        for query in queries {
+            assert_eq!(trie.floor(query).map(|(k, &v)| (k, v)), reference.range(..=query).next_back().map(|(&k, &v)| (k, v)));
+            assert_eq!(trie.ceil(query).map(|(k, &v)| (k, v)), reference.range(query..).next().map(|(&k, &v)| (k, v)));
+        }
+    }
+}
+
+#[test]
+fn floor_and_ceil_outside_the_keys() {
+    let trie = trie_of(&[10, 20]);
+
+    assert_eq!(trie.floor(9), None);
+    assert_eq!(trie.ceil(21), None);
+    assert_eq!(trie.floor(usize::MAX), Some((20, &20)));
+    assert_eq!(trie.ceil(0), Some((10, &10)));
+    assert_eq!(Trie::<u8>::new().floor(3), None);
+    assert_eq!(Trie::<u8>::new().ceil(3), None);
+}
+
+#[test]
+fn shares_root_only_for_clones() {
+    let (trie, _) = sample(3, 100);
+
+    assert!(trie.shares_root(&trie.clone()));
+    assert!(!trie.shares_root(&trie.update(5, 5)));
+    assert!(!trie.shares_root(&sample(3, 100).0));
+    assert!(Trie::<u8>::new().shares_root(&Trie::new()));
+    assert!(!Trie::new().shares_root(&trie));
+}
+
+#[test]
+fn insert_if_absent_keeps_existing_values() {
+    let (trie, reference) = sample(5, 200);
+    let key = *reference.keys().nth(17).unwrap();
+
+    let (same, inserted) = trie.insert_if_absent(key, 999);
+    assert!(!inserted);
+    assert!(same.shares_root(&trie));
+
+    let (more, inserted) = trie.insert_if_absent(123_456_789, 999);
+    assert!(inserted);
+    assert!(more.validate());
+    assert_eq!(more.len(), trie.len() + 1);
+    assert_eq!(more.get(123_456_789), Some(999));
+}
+
+#[test]
+fn trie_mut_insert_if_absent() {
+    let (trie, reference) = sample(5, 200);
+    let key = *reference.keys().nth(17).unwrap();
+    let mut trie_mut = trie.to_mut();
+
+    assert!(!trie_mut.insert_if_absent(key, 1));
+    assert!(trie_mut.insert_if_absent(7_777_777, 1));
+    assert!(!trie_mut.insert_if_absent(7_777_777, 2));
+
+    let trie = trie_mut.into_trie();
+    assert!(trie.validate());
+    assert_eq!(trie.get(7_777_777), Some(1));
+    assert_eq!(trie.get(key), reference.get(&key).cloned());
+}
+
+#[test]
+fn merge_accumulates_into_existing_values() {
+    let mut trie_mut = Trie::<usize>::new().to_mut();
+    let mut reference = BTreeMap::new();
+
+    for word in "a b c a b a d e a c".split(' ') {
+        let key = word.as_bytes()[0] as usize * 1_000_003;
+        trie_mut.merge(key, 1, |count, one| *count += one);
+        *reference.entry(key).or_insert(0) += 1;
+    }
+
+    let trie = trie_mut.into_trie();
+    assert!(trie.validate());
+    assert_eq!(entries(&trie), reference.into_iter().collect::<Vec<_>>());
+}
+
+#[test]
+fn merge_into_shared_nodes_leaves_the_original() {
+    let trie = trie_of(&[1, 2, 3]);
+    let mut trie_mut = trie.to_mut();
+    trie_mut.merge(2, 10, |value, more| *value += more);
+    trie_mut.merge(4, 10, |_, _| unreachable!());
+
+    assert_eq!(entries(&trie_mut.into_trie()), vec![(1, 1), (2, 12), (3, 3), (4, 10)]);
+    assert_eq!(entries(&trie), vec![(1, 1), (2, 2), (3, 3)]);
+}
+
+#[test]
+fn iter_subtrees_partition_the_entries() {
+    let shift = usize::BITS as usize - 5;
+
+    for seed in 1..20 {
+        let (trie, _) = sample(seed, 300);
+        let mut all = Vec::new();
+
+        for (prefix, prefix_bits, subtree) in trie.iter_subtrees() {
+            assert_eq!(prefix_bits, 5);
+            assert!(subtree.validate());
+            assert_eq!(subtree, trie.subtree(prefix, prefix_bits));
+            assert!(subtree.iter().all(|(key, _)| key >> shift == prefix >> shift));
+            all.extend(entries(&subtree));
+        }
+
+        assert_eq!(all, entries(&trie));
+    }
+}
+
+#[test]
+fn iter_subtrees_reassemble_the_original() {
+    let (trie, _) = sample(135, 500);
+    let reassembled: Trie<u64> = trie.iter_subtrees()
+        .flat_map(|(_, _, subtree)| subtree.into_iter())
+        .collect();
+
+    assert_eq!(reassembled, trie);
+
+    let merged = trie.iter_subtrees()
+        .fold(Trie::new(), |merged, (_, _, subtree)| merged.update_all(subtree));
+
+    assert_eq!(merged, trie);
+}
+
+#[test]
+fn iter_subtrees_of_small_tries() {
+    assert_eq!(trie_of(&[12345]).iter_subtrees().count(), 1);
+    assert_eq!(Trie::<u8>::new().iter_subtrees().count(), 0);
+}
+
+#[test]
+fn unique_node_count_counts_child_allocations() {
+    let top = 1 << (usize::BITS - 1);
+
+    assert_eq!(Trie::<u8>::new().unique_node_count(), 0);
+    assert_eq!(trie_of(&[5]).unique_node_count(), 0);
+    assert_eq!(trie_of(&[0, top]).unique_node_count(), 1);
+    assert_eq!(trie_of(&[0, 1, top]).unique_node_count(), trie_of(&[0, 1]).unique_node_count());
+}
+
+#[test]
+fn unique_node_count_after_an_update() {
+    let (trie, _) = sample(8, 2000);
+    let count = trie.unique_node_count();
+    let updated = trie.update(trie.min_key().unwrap(), 0);
+
+    assert!(count > 10);
+    assert_eq!(trie.clone().unique_node_count(), count);
+    assert_eq!(updated.unique_node_count(), count);
+}
+
+#[test]
+fn split_retain_sides_reunite() {
+    for seed in 1..20 {
+        let (trie, _) = sample(seed, 300);
+        let (kept, removed) = trie.clone().split_retain(|_, value| value % 3 == 1);
+
+        assert!(kept.validate() && removed.validate());
+        assert!(kept.iter().all(|(_, value)| value % 3 == 1));
+        assert!(removed.iter().all(|(_, value)| value % 3 != 1));
+        assert_eq!(kept.update_all(removed.into_iter()), trie);
+    }
+}
+
+#[test]
+fn find_map_returns_the_first_match() {
+    let (trie, reference) = sample(4, 300);
+    let expected = reference.iter().find(|entry| *entry.1 > 150).map(|(&key, &value)| (key, value * 2));
+    let mut visited = Vec::new();
+    let found = trie.find_map(|key, &value| {
+        visited.push(key);
+        if value > 150 { Some((key, value * 2)) } else { None }
+    });
+
+    assert_eq!(found, expected);
+    assert_eq!(visited.last(), expected.as_ref().map(|entry| &entry.0));
+    assert_eq!(trie.find_map(|_, _| None::<u8>), None);
+}
+
+#[test]
+fn keys_and_values_in_range_project_the_range() {
+    let (trie, reference) = sample(9, 300);
+    let mut rng = Rng(77);
+
+    for _ in 0..50 {
+        let (a, b) = (rng.key(), rng.key());
+        let (lo, hi) = (a.min(b), a.max(b));
+
+        assert_eq!(trie.keys_in_range(lo..hi).collect::<Vec<_>>(), reference.range(lo..hi).map(|(&k, _)| k).collect::<Vec<_>>());
+        assert_eq!(trie.values_in_range(lo..=hi).collect::<Vec<_>>(), reference.range(lo..=hi).map(|(_, v)| v).collect::<Vec<_>>());
+    }
+
+    assert_eq!(trie.keys_in_range(..).count(), trie.len());
+    assert_eq!(Trie::<u8>::new().values_in_range(..).next(), None);
+}
+
+#[test]
+fn remove_sorted_matches_remove_all() {
+    for seed in 1..40 {
+        let (trie, reference) = sample(seed, 300);
+        let mut rng = Rng(seed ^ 0xabc);
+        let mut keys: Vec<_> = reference.keys().cloned().filter(|_| rng.next().is_multiple_of(3)).collect();
+        keys.extend((0..50).map(|_| rng.key()));
+        keys.sort();
+
+        let removed = trie.remove_sorted(keys.iter().cloned());
+        assert!(removed.validate());
+        assert_eq!(removed, trie.remove_all(keys.iter().cloned()));
+    }
+}
+
+#[test]
+fn remove_sorted_of_every_key_and_of_none() {
+    let (trie, reference) = sample(3, 300);
+    let doubled = reference.keys().flat_map(|&key| vec![key, key]);
+
+    assert!(trie.remove_sorted(reference.keys().cloned()).is_empty());
+    assert!(trie.remove_sorted(doubled).is_empty());
+    assert!(trie.remove_sorted(vec![]).shares_root(&trie));
+}
+
+#[test]
+#[cfg(debug_assertions)]
+#[should_panic(expected = "sorted")]
+fn remove_sorted_rejects_unsorted_keys_in_debug_builds() {
+    trie_of(&[1, 2, 3]).remove_sorted(vec![3, 1]);
+}
+
+#[test]
+fn map_keys_with_increasing_transforms() {
+    let trie: Trie<u64> = (0..500).map(|key| (key * 37, key as u64)).collect();
+    let shifted = trie.map_keys(|key| key + 1_000_000);
+    let scaled = trie.map_keys(|key| key * 3);
+
+    assert!(shifted.validate() && scaled.validate());
+    assert_eq!(shifted.len(), 500);
+    for key in 0..500 {
+        assert_eq!(shifted.get(key * 37 + 1_000_000), Some(key as u64));
+        assert_eq!(scaled.get(key * 111), Some(key as u64));
+    }
+    assert!(Trie::<u8>::new().map_keys(|key| key + 1).is_empty());
+}
+
+#[test]
+#[cfg(debug_assertions)]
+#[should_panic]
+fn map_keys_rejects_decreasing_transforms_in_debug_builds() {
+    trie_of(&[1, 2, 3]).map_keys(|key| 10 - key);
+}
+
+#[test]
+fn eq_by_compares_with_the_given_function() {
+    let exact: Trie<f64> = (0..100).map(|key| (key * 7, key as f64)).collect();
+    let close: Trie<f64> = (0..100).map(|key| (key * 7, key as f64 + 1e-9)).collect();
+    let ints: Trie<i32> = (0..100).map(|key| (key * 7, key as i32)).collect();
+
+    assert!(exact.eq_by(&close, |a, b| (a - b).abs() < 1e-6));
+    assert!(!exact.eq_by(&close, |a, b| a == b));
+    assert!(exact.eq_by(&ints, |&a, &b| a == b as f64));
+    assert!(!exact.eq_by(&ints.remove(7).update(8, 1), |&a, &b| a == b as f64));
+    assert!(!exact.eq_by(&ints.remove(7), |_, _| true));
+    assert!(Trie::<u8>::new().eq_by(&Trie::<()>::new(), |_, _| false));
+}
+
+#[test]
+fn restrict_to_keeps_the_shared_keys() {
+    for seed in 1..30 {
+        let (trie, reference) = sample(seed, 300);
+        let mut rng = Rng(seed * 5 + 3);
+        let mut filter: Trie<()> = reference.keys().filter(|_| rng.next().is_multiple_of(2)).map(|&key| (key, ())).collect();
+        filter = filter.update_all((0..100).map(|_| (rng.key(), ())));
+
+        let restricted = trie.restrict_to(&filter);
+        let expected: Vec<_> = reference.iter()
+            .filter(|entry| filter.contains_key(*entry.0))
+            .map(|(&key, &value)| (key, value))
+            .collect();
+
+        assert!(restricted.validate());
+        assert_eq!(entries(&restricted), expected);
+    }
+}
+
+#[test]
+fn restrict_to_itself_and_to_disjoint_keys() {
+    let (trie, _) = sample(4, 300);
+    let disjoint: Trie<u8> = (0..50).map(|key| (key * 2 + 1_000_000_001, 0)).collect();
+
+    assert_eq!(trie.restrict_to(&trie), trie);
+    assert!(trie.restrict_to(&disjoint).is_empty());
+    assert!(trie.restrict_to(&Trie::<u8>::new()).is_empty());
+}
+
+#[test]
+fn get_disjoint_mut_borrows_distinct_keys() {
+    let (trie, reference) = sample(6, 300);
+    let keys: Vec<_> = reference.keys().cloned().collect();
+    let (a, b, c) = (keys[3], keys[150], keys[250]);
+    let mut trie_mut = trie.to_mut();
+
+    {
+        let [first, second, third, absent] = trie_mut.get_disjoint_mut([a, b, c, 424_242]);
+        assert!(absent.is_none());
+
+        let (first, second, third) = (first.unwrap(), second.unwrap(), third.unwrap());
+        std::mem::swap(first, second);
+        *third += 1000;
+    }
+
+    let updated = trie_mut.into_trie();
+    assert!(updated.validate());
+    assert_eq!(updated.get(a), reference.get(&b).cloned());
+    assert_eq!(updated.get(b), reference.get(&a).cloned());
+    assert_eq!(updated.get(c), reference.get(&c).map(|value| value + 1000));
+    assert_eq!(trie.get(a), reference.get(&a).cloned());
+}
+
+#[test]
+fn get_disjoint_mut_of_absent_keys() {
+    let mut trie_mut = trie_of(&[1, 2]).to_mut();
+
+    assert!(trie_mut.get_disjoint_mut([3, 4]).iter().all(Option::is_none));
+    assert!(Trie::<u8>::new().to_mut().get_disjoint_mut([0]).iter().all(Option::is_none));
+    assert_eq!(trie_mut.into_trie(), trie_of(&[1, 2]));
+}
+
+#[test]
+#[should_panic(expected = "repeated")]
+fn get_disjoint_mut_rejects_duplicate_keys() {
+    trie_of(&[1, 2]).to_mut().get_disjoint_mut([1, 2, 1]);
+}
+
+#[test]
+#[should_panic(expected = "repeated")]
+fn get_disjoint_mut_rejects_duplicate_absent_keys() {
+    trie_of(&[1, 2]).to_mut().get_disjoint_mut([5, 5]);
+}
+
+#[test]
+fn extract_range_copies_the_entries() {
+    let (trie, reference) = sample(12, 400);
+    let range = 100..1 << 30;
+    let extracted = trie.extract_range(range.clone());
+
+    assert!(extracted.validate());
+    assert_eq!(entries(&extracted), reference.range(range).map(|(&k, &v)| (k, v)).collect::<Vec<_>>());
+    assert_eq!(entries(&trie), reference.into_iter().collect::<Vec<_>>());
+}
+
+#[test]
+fn extract_range_shares_no_nodes() {
+    let trie = trie_of(&(0..1000).collect::<Vec<_>>());
+    let extracted = trie.extract_range(..);
+
+    assert_eq!(extracted, trie);
+    assert!(!extracted.shares_root(&trie));
+    assert!((0..1000).all(|key| extracted.leaf_ptr(key) != trie.leaf_ptr(key)));
+    assert!(trie.extract_range(5000..).is_empty());
+}
+
+#[test]
+fn group_count_counts_each_group() {
+    let (trie, reference) = sample(13, 300);
+    let mut expected = std::collections::HashMap::new();
+
+    for value in reference.values() {
+        *expected.entry(value % 3).or_insert(0) += 1;
+    }
+
+    assert_eq!(trie.group_count(|_, value| value % 3), expected);
+    assert_eq!(trie.group_count(|_, _| ()).get(&()), Some(&trie.len()));
+    assert!(Trie::<u8>::new().group_count(|key, _| key).is_empty());
+}
+
+#[test]
+fn splice_replaces_the_range() {
+    let trie: Trie<u64> = (0..100).map(|key| (key * 10, key as u64)).collect();
+    let replacement = vec![(205, 1), (300, 2), (2000, 3), (50, 4)];
+    let spliced = trie.splice(200..500, replacement.clone());
+
+    let mut expected: BTreeMap<_, _> = entries(&trie).into_iter().filter(|entry| !(200..500).contains(&entry.0)).collect();
+    expected.extend(replacement);
+
+    assert!(spliced.validate());
+    assert_eq!(entries(&spliced), expected.into_iter().collect::<Vec<_>>());
+}
+
+#[test]
+fn splice_with_empty_parts() {
+    let trie = trie_of(&[1, 5, 9]);
+
+    assert_eq!(trie.splice(2..5, vec![]), trie);
+    assert_eq!(trie.splice(.., vec![]), Trie::new());
+    assert_eq!(Trie::new().splice(.., vec![(3, 3)]), trie_of(&[3]));
+}
+
+#[test]
+fn prefix_count_matches_the_prefix_range() {
+    let (trie, reference) = sample(148, 2000);
+    let probes: Vec<usize> = reference.keys().cloned().step_by(41)
+        .chain(vec![0, 98765, usize::MAX])
+        .collect();
+
+    for prefix_bits in (0..=60).step_by(5) {
+        for &prefix in &probes {
+            let (lo, hi) = prefix_range(prefix, prefix_bits);
+
+            assert_eq!(trie.prefix_count(prefix, prefix_bits), reference.range(lo..=hi).count(),
+                "prefix {:#x} of {} bits", prefix, prefix_bits);
+        }
+    }
+}
+
+#[test]
+fn prefix_count_after_updates_and_removals() {
+    let mut trie = trie_of(&[1, 2, 40, 1 << 20]);
+    assert_eq!(trie.prefix_count(0, 60), 2);
+    assert_eq!(trie.prefix_count(0, 0), 4);
+
+    trie = trie.update(3, 3).remove(1 << 20);
+    assert_eq!(trie.prefix_count(0, 60), 3);
+    assert_eq!(trie.prefix_count(0, 0), 4);
+    assert_eq!(trie.prefix_count(1 << 20, 50), 0);
+    assert_eq!(Trie::<u8>::new().prefix_count(0, 0), 0);
+}
+
+#[test]
+fn try_fold_of_a_successful_fold() {
+    let (trie, reference) = sample(149, 300);
+    let sum: Result<u64, ()> = trie.try_fold(0, |acc, _, value| Ok(acc + value));
+
+    assert_eq!(sum, Ok(reference.values().sum()));
+    assert_eq!(Trie::<u64>::new().try_fold(7, |_, _, _| Err::<u64, ()>(())), Ok(7));
+}
+
+#[test]
+fn try_fold_stops_at_the_first_error() {
+    let (trie, reference) = sample(149, 300);
+    let mut calls = 0;
+    let result: Result<usize, usize> = trie.try_fold(0, |acc, key, _| {
+        calls += 1;
+        if calls == 40 { Err(key) } else { Ok(acc + 1) }
+    });
+
+    assert_eq!(result, Err(*reference.keys().nth(39).unwrap()));
+    assert_eq!(calls, 40);
+}
+
+/// Value which counts how many times it was cloned
+#[derive(Debug, PartialEq)]
+struct Counted(u64);
+
+static CLONES: AtomicUsize = AtomicUsize::new(0);
+
+impl Clone for Counted {
+    fn clone(&self) -> Counted {
+        CLONES.fetch_add(1, Ordering::SeqCst);
+        Counted(self.0)
+    }
+}
+
+#[test]
+fn into_iter_sorted_moves_unshared_values() {
+    let (trie, reference) = sample(151, 300);
+    let counted = trie.try_map(|_, &value| Ok::<_, ()>(Counted(value))).unwrap();
+
+    let before = CLONES.load(Ordering::SeqCst);
+    let drained: Vec<_> = counted.into_iter_sorted().map(|(key, value)| (key, value.0)).collect();
+
+    assert_eq!(CLONES.load(Ordering::SeqCst), before);
+    assert_eq!(drained, reference.into_iter().collect::<Vec<_>>());
+}
+
+#[test]
+fn into_iter_of_a_shared_trie_clones_the_values() {
+    let (trie, _) = sample(151, 300);
+    let shared = trie.clone();
+
+    assert_eq!(trie.into_iter().collect::<Vec<_>>(), entries(&shared));
+}
+
+#[test]
+fn update_path_inserts_modifies_and_removes() {
+    let mut trie = trie_of(&[1, 2, 3]).to_mut();
+
+    trie.update_path(40, |old| { assert_eq!(old, None); Some(40) });
+    trie.update_path(2, |old| old.map(|value| value * 10));
+    trie.update_path(3, |old| { assert_eq!(old, Some(3)); None });
+    trie.update_path(99, |old| { assert_eq!(old, None); None });
+
+    let trie = trie.into_trie();
+    assert!(trie.validate());
+    assert_eq!(trie.len(), 3);
+    assert_eq!(entries(&trie), vec![(1, 1), (2, 20), (40, 40)]);
+}
+
+#[test]
+fn update_path_matches_a_btreemap() {
+    let (trie, mut reference) = sample(152, 200);
+    let keys: Vec<usize> = reference.keys().cloned().collect();
+    let mut trie = trie.to_mut();
+    let mut rng = Rng(152);
+
+    for round in 0..400 {
+        let key = if round % 2 == 0 { keys[rng.next() as usize % keys.len()] } else { rng.key() };
+        let current = reference.get(&key).cloned();
+        let new = match round % 3 {
+            0 => None,
+            1 => Some(round as u64),
+            _ => current.map(|value| value + 1),
+        };
+
+        trie.update_path(key, |old| { assert_eq!(old, current); new });
+        match new {
+            Some(value) => reference.insert(key, value),
+            None => reference.remove(&key),
+        };
+    }
+
+    let trie = trie.into_trie();
+    assert!(trie.validate());
+    assert_eq!(entries(&trie), reference.into_iter().collect::<Vec<_>>());
+}
+
+#[test]
+fn membership_bloom_has_no_false_negatives() {
+    let (trie, reference) = sample(153, 500);
+    let bloom = trie.membership_bloom(4000);
+
+    assert_eq!(bloom.len(), 63);
+    assert!(reference.keys().all(|&key| bloom_may_contain(&bloom, key)));
+
+    let mut rng = Rng(153);
+    let false_positives = (0..1000).map(|_| rng.key())
+        .filter(|key| !reference.contains_key(key) && bloom_may_contain(&bloom, *key))
+        .count();
+    assert!(false_positives < 200, "{} false positives", false_positives);
+}
+
+#[test]
+fn membership_bloom_of_an_empty_trie() {
+    let bloom = Trie::<u8>::new().membership_bloom(64);
+
+    assert_eq!(bloom, vec![0]);
+    assert!(!bloom_may_contain(&bloom, 5usize));
+    assert!(!bloom_may_contain(&[], 5usize));
+}
+
+#[test]
+#[should_panic]
+fn membership_bloom_rejects_zero_bits() {
+    Trie::<u8>::new().membership_bloom(0);
+}
+
+#[test]
+fn iter_chunks_concatenate_to_iter() {
+    let (trie, _) = sample(154, 300);
+    let chunks: Vec<_> = trie.iter_chunks(7).collect();
+    let (last, full) = chunks.split_last().unwrap();
+
+    assert!(full.iter().all(|chunk| chunk.len() == 7));
+    assert!(!last.is_empty() && last.len() <= 7);
+    assert_eq!(chunks.concat(), trie.iter().collect::<Vec<_>>());
+}
+
+#[test]
+fn iter_chunks_of_small_tries() {
+    assert_eq!(Trie::<u8>::new().iter_chunks(3).count(), 0);
+    assert_eq!(trie_of(&[1, 2, 3]).iter_chunks(3).count(), 1);
+    assert_eq!(trie_of(&[1, 2, 3]).iter_chunks(1).map(|chunk| chunk.len()).collect::<Vec<_>>(), vec![1, 1, 1]);
+}
+
+#[test]
+#[should_panic]
+fn iter_chunks_rejects_zero_size() {
+    let _ = Trie::<u8>::new().iter_chunks(0);
+}
+
+#[test]
+fn range_eq_ignores_boundary_children_outside_the_range() {
+    let trie = trie_of(&[0, 1]);
+    let grown = trie.update(33, 2);
+
+    assert!(trie.range_eq(&grown, 0..33));
+    assert!(trie.range_eq(&grown, 2..=32));
+    assert!(grown.range_eq(&trie, 2..=32));
+    assert!(!trie.range_eq(&grown, 0..=33));
+    assert!(!grown.range_eq(&trie, 33..));
+}
+
+#[test]
+fn range_eq_descends_keys_sharing_high_chunks() {
+    let a = 31 << 59;
+    let b = a | 5 << 54;
+    let deeper = b | 1 << 49;
+    let d = a | 7 << 54;
+
+    assert!(trie_of(&[a, b]).range_eq(&trie_of(&[a, 0]), a..=a));
+    assert!(!trie_of(&[a, b, deeper, 0, d]).range_eq(&trie_of(&[a, 0, d]), a..=deeper));
+    assert!(trie_of(&[a, b, deeper, 0, d]).range_eq(&trie_of(&[a, 0, d]), ..b));
+
+    let keys: Vec<usize> = (0..6).flat_map(|high| (0..4).map(move |low| a | high << 54 | low << 49)).collect();
+
+    for (pos, &key) in keys.iter().enumerate() {
+        let trie = trie_of(&keys[..pos]);
+        let other = trie_of(&keys[pos..]);
+
+        for &lo in &keys {
+            for &hi in keys.iter().filter(|&&hi| hi >= lo) {
+                let expected = trie.range(lo..=hi).eq(other.range(lo..=hi));
+                assert_eq!(trie.range_eq(&other, lo..=hi), expected, "{} {} {}", key, lo, hi);
+            }
+        }
+    }
+}
+
+#[test]
+fn range_eq_matches_a_comparison_of_ranges() {
+    for seed in 0..20 {
+        let (trie, reference) = sample(155 + seed, 300);
+        let keys: Vec<usize> = reference.keys().cloned().collect();
+        let mut rng = Rng(seed);
+        let key = keys[rng.next() as usize % keys.len()];
+        let updated = trie.update(key, 123456);
+        let removed = trie.remove(key);
+        let rebuilt: Trie<u64> = reference.iter().map(|(&key, &value)| (key, value)).collect();
+
+        for _ in 0..50 {
+            let (a, b) = (rng.key(), rng.key());
+            let (lo, hi) = (a.min(b), a.max(b));
+
+            assert!(trie.range_eq(&rebuilt, lo..=hi));
+            assert_eq!(trie.range_eq(&updated, lo..=hi), !(lo..=hi).contains(&key));
+            assert_eq!(trie.range_eq(&updated, lo..hi), !(lo..hi).contains(&key));
+            assert_eq!(trie.range_eq(&removed, lo..=hi), !(lo..=hi).contains(&key));
+            assert_eq!(removed.range_eq(&trie, lo..=hi), !(lo..=hi).contains(&key));
+        }
+
+        assert!(trie.range_eq(&updated, key..key));
+        assert!(!trie.range_eq(&updated, key..=key));
+        assert!(!trie.range_eq(&Trie::new(), ..));
+    }
+}
+
+#[test]
+fn level_bitset_follows_the_prefix() {
+    let top = 1 << (usize::BITS - 5);
+    let trie = trie_of(&[0, 1, 33, top]);
+    let last = (usize::BITS as usize - 1) / 5;
+
+    assert_eq!(trie.level_bitset(0, 0).unwrap(), 0b11);
+    assert_eq!(trie.level_bitset(0, last - 1).unwrap(), 0b101);
+    assert_eq!(trie.level_bitset(0, last).unwrap(), 0b11);
+    assert!((1..last - 1).all(|depth| trie.level_bitset(0, depth).unwrap() == 1));
+}
+
+#[test]
+fn level_bitset_of_leaves_and_missing_paths() {
+    let top = 1 << (usize::BITS - 5);
+    let trie = trie_of(&[0, 1, 33, top]);
+    let last = (usize::BITS as usize - 1) / 5;
+
+    assert_eq!(trie.level_bitset(top, 1), None);
+    assert_eq!(trie.level_bitset(33, last), None);
+    assert_eq!(trie.level_bitset(2 << (usize::BITS - 5), 1), None);
+    assert_eq!(Trie::<u8>::new().level_bitset(0, 0), None);
+    assert_eq!(trie_of(&[5]).level_bitset(5, 0), None);
+}
+
+#[test]
+fn take_first_and_last_n_match_the_ordered_entries() {
+    let (trie, reference) = sample(158, 300);
+    let ordered: Vec<_> = reference.into_iter().collect();
+
+    for &n in &[0, 1, 2, 5, 50, 150, 299, 300, 1000] {
+        let first = trie.take_first_n(n);
+        let last = trie.take_last_n(n);
+        let skipped = ordered.len().saturating_sub(n);
+
+        assert!(first.validate() && last.validate(), "{} entries", n);
+        assert_eq!(entries(&first), ordered[..n.min(ordered.len())].to_vec());
+        assert_eq!(entries(&last), ordered[skipped..].to_vec());
+    }
+}
+
+#[test]
+fn take_first_and_last_n_of_small_tries() {
+    let trie = trie_of(&[3, 1 << 40, usize::MAX]);
+
+    assert_eq!(trie.take_first_n(1), trie_of(&[3]));
+    assert_eq!(trie.take_last_n(1), trie_of(&[usize::MAX]));
+    assert!(trie.take_first_n(3).shares_root(&trie));
+    assert!(Trie::<u8>::new().take_last_n(2).is_empty());
+}
+
+#[test]
+fn value_counts_matches_a_manual_count() {
+    let (trie, reference) = sample(159, 500);
+    let trie = trie.try_map(|_, value| Ok::<_, ()>(value % 7)).unwrap();
+    let mut expected = HashMap::new();
+
+    for value in reference.values() {
+        *expected.entry(value % 7).or_insert(0) += 1;
+    }
+
+    assert_eq!(trie.value_counts(), expected);
+}
+
+#[test]
+fn value_counts_of_repeated_values() {
+    let trie: Trie<u8> = (0..300).map(|key| (key * 7919, (key % 5) as u8)).collect();
+    let counts = trie.value_counts();
+
+    assert_eq!(counts.len(), 5);
+    assert!((0..5).all(|value| counts[&value] == 60));
+    assert!(Trie::<u8>::new().value_counts().is_empty());
+}
+
+#[test]
+fn fill_range_sets_every_key_in_the_range() {
+    let (trie, reference) = sample(160, 200);
+    let mut rng = Rng(160);
+
+    for round in 0..20 {
+        let start = if round % 2 == 0 { rng.next() as usize % 300 } else { rng.key().saturating_sub(2000) };
+        let count = rng.next() as usize % 1500;
+        let filled = trie.fill_range(start, count, 9999);
+
+        let mut expected = reference.clone();
+        expected.extend((start..start + count).map(|key| (key, 9999)));
+
+        assert!(filled.validate());
+        assert_eq!(filled.len(), expected.len());
+        assert_eq!(entries(&filled), expected.into_iter().collect::<Vec<_>>());
+    }
+}
+
+#[test]
+fn fill_range_of_empty_ranges_and_tries() {
+    let trie = trie_of(&[1, 2]);
+
+    assert!(trie.fill_range(5, 0, 0).shares_root(&trie));
+    assert_eq!(Trie::new().fill_range(10, 3, 7), vec![(10, 7), (11, 7), (12, 7)].into_iter().collect());
+    assert_eq!(trie.fill_range(usize::MAX, 1, 0).max_key(), Some(usize::MAX));
+}
+
+#[test]
+#[should_panic]
+fn fill_range_past_usize_max_panics() {
+    Trie::new().fill_range(usize::MAX, 2, 0);
+}
+
+#[test]
+fn truncate_keeps_the_keys_below() {
+    let (trie, reference) = sample(161, 500);
+    let probes: Vec<usize> = reference.keys().cloned().step_by(23)
+        .chain(vec![0, 1, 12345, usize::MAX])
+        .collect();
+
+    for &upto in &probes {
+        let truncated = trie.truncate(upto);
+
+        assert!(truncated.validate(), "truncated at {}", upto);
+        assert_eq!(entries(&truncated), reference.range(..upto).map(|(&k, &v)| (k, v)).collect::<Vec<_>>());
+        assert_eq!(trie.keys_below(upto), truncated);
+    }
+}
+
+#[test]
+fn truncate_shares_the_subtrees_below() {
+    static COMPARED: AtomicUsize = AtomicUsize::new(0);
+
+    #[derive(Debug, Clone)]
+    struct Compared(usize);
+
+    impl PartialEq for Compared {
+        fn eq(&self, other: &Compared) -> bool {
+            COMPARED.fetch_add(1, Ordering::SeqCst);
+            self.0 == other.0
+        }
+    }
+
+    // 40 keys under each child of the root, cut within the 21st child
+    let trie: Trie<Compared> = (0..32 * 40)
+        .map(|key| ((key % 32) << (usize::BITS - 5) | key, Compared(key)))
+        .collect();
+    let upto = 20 << (usize::BITS - 5) | 500;
+    let truncated = trie.truncate(upto);
+
+    assert!(truncated.validate());
+    assert_eq!(truncated.len(), 20 * 40 + 15);
+
+    // Only the leaves of the rebuilt boundary child are compared
+    assert!(truncated == trie.truncate(upto));
+    assert_eq!(COMPARED.load(Ordering::SeqCst), 15);
+}
+
+#[test]
+fn compose_maps_through_both_tries() {
+    let mids: Trie<usize> = vec![(1, 10), (2, 20), (3, 99), (usize::MAX, 10)].into_iter().collect();
+    let values: Trie<&str> = vec![(10, "ten"), (20, "twenty"), (30, "thirty")].into_iter().collect();
+    let composed = mids.compose(&values);
+
+    assert!(composed.validate());
+    assert_eq!(entries(&composed), vec![(1, "ten"), (2, "twenty"), (usize::MAX, "ten")]);
+    assert!(mids.compose(&Trie::<u8>::new()).is_empty());
+    assert!(Trie::<usize>::new().compose(&values).is_empty());
+}
+
+#[test]
+fn first_free_run_matches_a_brute_force_search() {
+    let keys: Vec<usize> = (0..5).chain(vec![10, 12]).chain(20..30).chain(vec![100]).collect();
+    let trie = trie_of(&keys);
+
+    for start in 0..110 {
+        for length in 1..15 {
+            let expected = (start..).find(|&index| (index..index + length).all(|key| !keys.contains(&key)));
+
+            assert_eq!(trie.first_free_run(start, length), expected, "{} keys from {}", length, start);
+        }
+    }
+}
+
+#[test]
+fn first_free_run_near_usize_max() {
+    let trie = trie_of(&[usize::MAX - 10]);
+
+    assert_eq!(trie.first_free_run(usize::MAX - 20, 5), Some(usize::MAX - 20));
+    assert_eq!(trie.first_free_run(usize::MAX - 12, 5), Some(usize::MAX - 9));
+    assert_eq!(trie.first_free_run(usize::MAX - 5, 10), None);
+    assert_eq!(trie.first_free_run(usize::MAX, 0), Some(usize::MAX));
+}
+
+#[test]
+fn retain_values_matches_retain() {
+    let (trie, _) = sample(164, 400);
+    let signed: Trie<i32> = trie.try_map(|_, &value| Ok::<_, ()>(value as i32 % 7 - 3)).unwrap();
+    let positive = signed.retain_values(|&value| value > 0);
+
+    assert!(positive.validate());
+    assert_eq!(positive, signed.retain(|_, &value| value > 0));
+    assert!(positive.iter().all(|(_, &value)| value > 0));
+    assert!(signed.retain_values(|_| true).shares_root(&signed));
+}
+
+#[test]
+fn cloned_pairs_match_the_owned_entries() {
+    let (trie, reference) = sample(165, 300);
+    let mut pairs = trie.cloned_pairs();
+
+    assert_eq!(pairs.len(), reference.len());
+    pairs.next();
+    assert_eq!(pairs.len(), reference.len() - 1);
+
+    assert_eq!(trie.cloned_pairs().collect::<Vec<_>>(), trie.clone().into_iter().collect::<Vec<_>>());
+    assert_eq!(Trie::<u8>::new().cloned_pairs().len(), 0);
+}
+
+#[test]
+fn commit_snapshots_survive_later_edits() {
+    let mut trie_mut = Trie::new().to_mut();
+    let mut snapshots = Vec::new();
+
+    for round in 0..10 {
+        for key in round * 100..round * 100 + 50 {
+            trie_mut.insert(key * 7919, round);
+        }
+        trie_mut.remove(round * 7919);
+        snapshots.push(trie_mut.commit());
+    }
+
+    for (round, snapshot) in snapshots.iter().enumerate() {
+        assert!(snapshot.validate());
+        assert_eq!(snapshot.len(), (round + 1) * 49);
+        assert_eq!(snapshot.get((round * 100 + 1) * 7919), Some(round));
+        assert_eq!(snapshot.get((round * 100 + 100) * 7919), None);
+    }
+
+    assert_eq!(trie_mut.len(), snapshots.last().unwrap().len());
+    assert_eq!(trie_mut.into_trie(), snapshots.pop().unwrap());
+}
+
+#[test]
+fn commit_of_an_empty_session() {
+    let mut trie_mut = Trie::<u8>::new().to_mut();
+
+    assert!(trie_mut.commit().is_empty());
+    trie_mut.insert(3, 3);
+    assert_eq!(trie_mut.commit(), Trie::new().update(3, 3));
+    assert_eq!(trie_mut.len(), 1);
+}
+
+#[test]
+fn scan_computes_prefix_sums() {
+    let (trie, reference) = sample(167, 300);
+    let sums = trie.scan(0, |total, _, &value| {
+        *total += value;
+        *total
+    });
+
+    let mut total = 0;
+    let expected: Vec<_> = reference.iter().map(|(&key, &value)| {
+        total += value;
+        (key, total)
+    }).collect();
+
+    assert!(sums.validate());
+    assert_eq!(entries(&sums), expected);
+    assert!(Trie::<u8>::new().scan(0, |_, key, _| key).is_empty());
+}
+
+#[test]
+fn retain_counting_counts_the_dropped_entries() {
+    let (trie, reference) = sample(168, 400);
+
+    for modulus in 1..6 {
+        let (kept, removed) = trie.retain_counting(|_, value| value % modulus == 0);
+
+        assert!(kept.validate());
+        assert_eq!(removed, trie.len() - kept.len());
+        assert_eq!(removed, reference.values().filter(|&value| value % modulus != 0).count());
+        assert_eq!(kept, trie.retain(|_, value| value % modulus == 0));
+    }
+
+    assert_eq!(trie.retain_counting(|_, _| false).1, trie.len());
+    assert_eq!(Trie::<u8>::new().retain_counting(|_, _| false).1, 0);
+}
+
+#[test]
+fn as_dense_vec_of_dense_tries() {
+    let dense: Trie<usize> = (0..100).rev().map(|key| (key, key * 2)).collect();
+
+    assert_eq!(dense.as_dense_vec(), Some((0..100).map(|key| key * 2).collect()));
+    assert_eq!(trie_of(&[0]).as_dense_vec(), Some(vec![0]));
+    assert_eq!(Trie::<u8>::new().as_dense_vec(), Some(vec![]));
+}
+
+#[test]
+fn as_dense_vec_of_sparse_tries() {
+    let dense: Trie<usize> = (0..100).map(|key| (key, key)).collect();
+
+    assert_eq!(dense.remove(50).as_dense_vec(), None);
+    assert_eq!(dense.remove(0).as_dense_vec(), None);
+    assert_eq!(dense.update(101, 0).as_dense_vec(), None);
+    assert_eq!(trie_of(&[1]).as_dense_vec(), None);
+}
+
+#[test]
+fn take_while_keeps_the_leading_entries() {
+    let (trie, reference) = sample(171, 500);
+
+    for &threshold in &[0, 1, 100, 1 << 30, usize::MAX - 50, usize::MAX] {
+        let taken = trie.take_while(|key, _| key < threshold);
+
+        assert!(taken.validate());
+        assert_eq!(taken, trie.truncate(threshold));
+        assert_eq!(entries(&taken), reference.range(..threshold).map(|(&k, &v)| (k, v)).collect::<Vec<_>>());
+    }
+
+    assert!(trie.take_while(|_, _| true).shares_root(&trie));
+}
+
+#[test]
+fn take_while_stops_at_the_first_rejection() {
+    let trie = trie_of(&[1, 2, 3, 10, 4000, 5000]);
+    let mut visited = Vec::new();
+    let taken = trie.take_while(|key, &value| {
+        visited.push(key);
+        value % 2 == 1 || key < 3
+    });
+
+    assert_eq!(taken, trie_of(&[1, 2, 3]));
+    assert_eq!(visited, vec![1, 2, 3, 10]);
+}
+
+#[test]
+fn skip_while_keeps_the_trailing_entries() {
+    let (trie, _) = sample(172, 500);
+
+    for &threshold in &[0, 1, 100, 1 << 30, usize::MAX - 50, usize::MAX] {
+        let skipped = trie.skip_while(|key, _| key < threshold);
+        let expected: Trie<u64> = trie.range(threshold..).map(|(key, &value)| (key, value)).collect();
+
+        assert!(skipped.validate());
+        assert_eq!(skipped, expected);
+    }
+
+    assert!(trie.skip_while(|_, _| true).is_empty());
+    assert_eq!(trie.skip_while(|_, _| false), trie);
+}
+
+#[test]
+fn update_if_applies_accepted_updates() {
+    let trie = trie_of(&[1, 2]);
+    let swapped = trie.update_if(2, 20, |current| current == Some(&2)).unwrap();
+    let inserted = trie.update_if(3, 30, |current| current.is_none()).unwrap();
+
+    assert_eq!(entries(&swapped), vec![(1, 1), (2, 20)]);
+    assert_eq!(entries(&inserted), vec![(1, 1), (2, 2), (3, 30)]);
+}
+
+#[test]
+fn update_if_rejects_without_updating() {
+    let trie = trie_of(&[1, 2]);
+
+    assert_eq!(trie.update_if(2, 20, |current| current == Some(&5)), None);
+    assert_eq!(trie.update_if(3, 30, |current| current.is_some()), None);
+    assert_eq!(entries(&trie), vec![(1, 1), (2, 2)]);
+}
+
+#[test]
+fn iter_rev_yields_descending_keys() {
+    let (trie, reference) = sample(174, 300);
+    let reversed: Vec<_> = trie.iter_rev().map(|(key, &value)| (key, value)).collect();
+
+    assert!(reversed.windows(2).all(|pair| pair[0].0 > pair[1].0));
+    assert_eq!(reversed, reference.into_iter().rev().collect::<Vec<_>>());
+    assert_eq!(Trie::<u8>::new().iter_rev().next(), None);
+}
+
+#[test]
+fn retain_matching_keeps_the_allowed_keys() {
+    let (trie, reference) = sample(175, 400);
+    let allowed: HashSet<usize> = reference.keys().cloned().step_by(3).chain(vec![4, 5, 6]).collect();
+    let kept = trie.retain_matching(&allowed);
+
+    assert!(kept.validate());
+    assert_eq!(kept, trie.retain(|key, _| allowed.contains(&key)));
+    assert!(trie.retain_matching(&HashSet::new()).is_empty());
+}
+
+#[test]
+fn retain_in_ranges_matches_the_range_filter() {
+    let (trie, _) = sample(175, 400);
+    let ranges = vec![0..50, 120..180, 1 << 20..1 << 44, usize::MAX - 30..usize::MAX];
+    let kept = trie.retain_in_ranges(&ranges);
+
+    assert!(kept.validate());
+    assert_eq!(kept, trie.retain(|key, _| ranges.iter().any(|range| range.contains(&key))));
+    assert!(trie.retain_in_ranges(&[]).is_empty());
+    assert_eq!(trie.retain_in_ranges(&[0..100, 100..usize::MAX]), trie.remove(usize::MAX));
+}
+
+#[test]
+#[cfg(debug_assertions)]
+#[should_panic(expected = "Ranges should be sorted and disjoint")]
+fn retain_in_ranges_rejects_overlapping_ranges_in_debug_builds() {
+    trie_of(&[1]).retain_in_ranges(&[0..10, 5..20]);
+}
+
+#[test]
+fn coalesce_merges_adjacent_segments() {
+    let segments: Trie<(usize, usize)> = vec![(0, (0, 5)), (5, (5, 8)), (8, (8, 9)), (20, (20, 30)), (30, (30, 31)), (40, (40, 41))]
+        .into_iter().collect();
+    let merged = segments.coalesce(|&(start, end), &(next_start, next_end)| {
+        if end == next_start { Some((start, next_end)) } else { None }
+    });
+
+    assert!(merged.validate());
+    assert_eq!(entries(&merged), vec![(0, (0, 9)), (20, (20, 31)), (40, (40, 41))]);
+}
+
+#[test]
+fn coalesce_without_merges() {
+    let (trie, _) = sample(176, 300);
+
+    assert_eq!(trie.coalesce(|_, _| None), trie);
+    assert_eq!(trie.coalesce(|_, _| Some(0)), Trie::new().update(trie.min_key().unwrap(), 0));
+    assert!(Trie::<u8>::new().coalesce(|_, _| Some(0)).is_empty());
+}
+
+#[test]
+fn max_key_bits_across_level_boundaries() {
+    assert_eq!(Trie::<u8>::new().max_key_bits(), 0);
+    assert_eq!(trie_of(&[0]).max_key_bits(), 0);
+    assert_eq!(trie_of(&[1]).max_key_bits(), 1);
+    assert_eq!(trie_of(&[3, 31]).max_key_bits(), 5);
+    assert_eq!(trie_of(&[3, 32]).max_key_bits(), 6);
+    assert_eq!(trie_of(&[1023]).max_key_bits(), 10);
+    assert_eq!(trie_of(&[1, 1024]).max_key_bits(), 11);
+    assert_eq!(trie_of(&[7, usize::MAX]).max_key_bits(), usize::BITS);
+}
+
+#[test]
+fn get_many_mut_borrows_every_present_key() {
+    let (trie, reference) = sample(178, 300);
+    let keys: Vec<_> = reference.keys().cloned().step_by(30).collect();
+    let mut trie_mut = trie.to_mut();
+
+    for value in trie_mut.get_many_mut(&keys).unwrap() {
+        *value += 1000;
+    }
+
+    let updated = trie_mut.into_trie();
+    assert!(updated.validate());
+    for (key, &value) in &reference {
+        let bump = if keys.contains(key) { 1000 } else { 0 };
+        assert_eq!(updated.get(*key), Some(value + bump));
+    }
+    assert!(trie.to_mut().get_many_mut(&[]).unwrap().is_empty());
+}
+
+#[test]
+fn get_many_mut_keeps_the_order_of_the_indices() {
+    let mut trie_mut = trie_of(&[1, 2, 3]).to_mut();
+    let values: Vec<usize> = trie_mut.get_many_mut(&[3, 1, 2]).unwrap().into_iter().map(|value| *value).collect();
+
+    assert_eq!(values, vec![3, 1, 2]);
+}
+
+#[test]
+fn get_many_mut_reports_duplicate_indices() {
+    let mut trie_mut = trie_of(&[1, 2, 3]).to_mut();
+
+    assert_eq!(trie_mut.get_many_mut(&[1, 2, 1]).err(), Some(AliasError::Duplicate(1)));
+    assert_eq!(trie_mut.get_many_mut(&[7, 3, 7]).err(), Some(AliasError::Duplicate(7)));
+    assert_eq!(AliasError::Duplicate(1usize).to_string(), "Index 1 is requested more than once");
+}
+
+#[test]
+fn get_many_mut_reports_the_first_absent_index() {
+    let mut trie_mut = trie_of(&[1, 2, 3]).to_mut();
+
+    assert_eq!(trie_mut.get_many_mut(&[1, 9, 2, 8]).err(), Some(AliasError::Absent(9)));
+    assert_eq!(AliasError::Absent(9usize).to_string(), "Index 9 is not present");
+    assert_eq!(trie_mut.into_trie(), trie_of(&[1, 2, 3]));
+}
+
+#[test]
+fn path_keys_follow_the_routing_chunks() {
+    let (trie, reference) = sample(179, 300);
+
+    for &key in reference.keys().chain(&[12345, usize::MAX - 1000]) {
+        let path = trie.path_keys(key);
+        let expected: Vec<_> = (0..path.len()).map(|depth| Index32::convert(key, depth)).collect();
+
+        assert!(!path.is_empty());
+        assert_eq!(path, expected);
+    }
+}
+
+#[test]
+fn path_keys_stop_at_leaves_and_missing_children() {
+    let clustered = trie_of(&[0, 1]);
+    let depth = (usize::BITS as usize).div_ceil(5);
+
+    assert_eq!(clustered.path_keys(0).len(), depth);
+    assert_eq!(clustered.path_keys(1 << (usize::BITS - 1)).len(), 1);
+    assert_eq!(trie_of(&[5]).path_keys(5), vec![]);
+    assert_eq!(Trie::<u8>::new().path_keys(5), vec![]);
+}
+
+#[test]
+fn densify_range_fills_the_missing_keys() {
+    let (trie, reference) = sample(180, 300);
+
+    for &(start, end) in &[(0, 300), (150, 151), (1 << 30, (1 << 30) + 2000), (usize::MAX - 200, usize::MAX)] {
+        let dense = trie.densify_range(start, end);
+        let mut expected = reference.clone();
+
+        for key in start..end {
+            expected.entry(key).or_insert(0);
+        }
+
+        assert!(dense.validate());
+        assert_eq!(entries(&dense), expected.into_iter().collect::<Vec<_>>());
+    }
+}
+
+#[test]
+fn densify_range_of_empty_ranges() {
+    let trie = trie_of(&[1, 2]);
+
+    assert!(trie.densify_range(5, 5).shares_root(&trie));
+    assert!(trie.densify_range(9, 3).shares_root(&trie));
+    assert_eq!(Trie::<u8>::new().densify_range(0, 3), vec![(0, 0), (1, 0), (2, 0)].into_iter().collect());
+}
+
+#[test]
+fn merkle_root_depends_on_the_entries() {
+    let (trie, reference) = sample(181, 300);
+    let rebuilt: Trie<u64> = reference.iter().rev().map(|(&key, &value)| (key, value)).collect();
+    let first = trie.min_key().unwrap();
+
+    assert_eq!(trie.merkle_root(), rebuilt.merkle_root());
+    assert_ne!(trie.merkle_root(), trie.update(first, u64::MAX).merkle_root());
+    assert_ne!(trie.merkle_root(), trie.remove(first).merkle_root());
+    assert_eq!(Trie::<u8>::new().merkle_root(), Trie::<u8>::new().merkle_root());
+}
+
+#[test]
+fn merkle_root_with_rehashes_only_the_updated_path() {
+    let (trie, _) = sample(181, 300);
+    let updated = trie.update(12345, 0);
+    let mut cache = MerkleCache::new();
+
+    assert_eq!(trie.merkle_root_with(&mut cache), trie.merkle_root());
+    assert_eq!(cache.len(), trie.unique_node_count());
+
+    let before = cache.len();
+    assert_eq!(updated.merkle_root_with(&mut cache), updated.merkle_root());
+    assert!(cache.len() - before <= trie.path_keys(12345).len());
+}
+
+#[test]
+fn mask_levels_matches_a_per_key_filter() {
+    let (trie, _) = sample(182, 500);
+    let mut rng = Rng(182);
+
+    for levels in 0..4 {
+        let masks: Vec<Bitset> = (0..levels).map(|_| Bitset::from(rng.next() as u32)).collect();
+        let masked = trie.mask_levels(&masks);
+        let allowed = |key: usize| masks.iter().enumerate()
+            .all(|(depth, mask)| mask.get(Index32::convert(key, depth)));
+
+        assert!(masked.validate());
+        assert_eq!(masked, trie.retain(|key, _| allowed(key)));
+    }
+}
+
+#[test]
+fn mask_levels_masking_out_half_the_key_space() {
+    let (trie, _) = sample(182, 500);
+    let lower_half = Bitset::from(0x0000_ffff);
+    let top = |key: usize| Index32::convert(key, 0).num() < 16;
+
+    assert_eq!(trie.mask_levels(&[lower_half]), trie.retain(|key, _| top(key)));
+    assert!(trie.mask_levels(&[Bitset::new()]).is_empty());
+    assert_eq!(trie.mask_levels(&[]), trie);
+}
+
+#[test]
+fn leaf_ptr_is_shared_by_clones() {
+    let (trie, reference) = sample(183, 300);
+    let clone = trie.clone();
+    let key = *reference.keys().nth(100).unwrap();
+
+    assert_eq!(trie.leaf_ptr(key), clone.leaf_ptr(key));
+    assert_eq!(trie.leaf_ptr(key), trie.get_ref(key).map(|value| value as *const u64));
+    assert_eq!(trie.leaf_ptr(424_242), None);
+}
+
+#[test]
+fn leaf_ptr_changes_when_the_leaf_is_replaced() {
+    let (trie, _) = sample(183, 300);
+    let (key, other) = (trie.min_key().unwrap(), trie.max_key().unwrap());
+    let updated = trie.update(key, 0);
+
+    assert_ne!(trie.leaf_ptr(key), updated.leaf_ptr(key));
+    assert_eq!(trie.leaf_ptr(other), updated.leaf_ptr(other));
+}
+
+#[test]
+fn contains_sorted_matches_contains_key() {
+    let (trie, reference) = sample(184, 500);
+    let mut rng = Rng(184);
+    let mut keys: Vec<usize> = reference.keys().cloned().step_by(2)
+        .chain((0..500).map(|_| rng.key()))
+        .collect();
+    keys.sort();
+
+    let expected: Vec<_> = keys.iter().map(|&key| trie.contains_key(key)).collect();
+    assert_eq!(trie.contains_sorted(keys.iter().cloned()), expected);
+}
+
+#[test]
+fn contains_sorted_of_repeated_and_no_keys() {
+    let trie = trie_of(&[1, 2, usize::MAX]);
+
+    assert_eq!(trie.contains_sorted(vec![0, 1, 1, 2, 3, usize::MAX, usize::MAX]),
+        vec![false, true, true, true, false, true, true]);
+    assert!(trie.contains_sorted(vec![]).is_empty());
+    assert_eq!(Trie::<u8>::new().contains_sorted(vec![1, 2]), vec![false, false]);
+}
+
+#[test]
+#[cfg(debug_assertions)]
+#[should_panic(expected = "Keys should be sorted in ascending order")]
+fn contains_sorted_rejects_unsorted_keys_in_debug_builds() {
+    trie_of(&[1]).contains_sorted(vec![2, 1]);
+}
+
+#[test]
+fn segments_of_alternating_values() {
+    let trie: Trie<bool> = vec![(0, true), (1, true), (5, false), (6, true), (10, true), (11, false), (12, false), (20, true)]
+        .into_iter().collect();
+
+    assert_eq!(trie.segments(|_, &active| active), vec![0..2, 6..11, 20..21]);
+    assert_eq!(trie.segments(|_, &active| !active), vec![5..6, 11..13]);
+    assert!(trie.segments(|_, _| false).is_empty());
+    assert_eq!(trie.segments(|_, _| true), vec![0..21]);
+}
+
+#[test]
+fn segments_ending_at_usize_max() {
+    let trie = trie_of(&[3, usize::MAX - 1, usize::MAX]);
+
+    assert_eq!(trie.segments(|key, _| key > 3), vec![usize::MAX - 1..usize::MAX]);
+    assert_eq!(trie_of(&[usize::MAX]).segments(|_, _| true), vec![usize::MAX..usize::MAX]);
+    assert!(Trie::<u8>::new().segments(|_, _| true).is_empty());
+}
+
+#[test]
+fn segments_match_a_scan_of_the_entries() {
+    let (trie, reference) = sample(185, 300);
+    let passes = |key: usize, value: u64| !(key as u64 ^ value).is_multiple_of(3);
+    let mut expected: Vec<std::ops::Range<usize>> = Vec::new();
+    let mut previous_passed = false;
+
+    for (&key, &value) in &reference {
+        if passes(key, value) {
+            match expected.last_mut() {
+                Some(segment) if previous_passed => segment.end = key.saturating_add(1),
+                _ => expected.push(key..key.saturating_add(1)),
+            }
+        }
+        previous_passed = passes(key, value);
+    }
+
+    assert_eq!(trie.segments(|key, &value| passes(key, value)), expected);
+}
+
+#[test]
+fn run_length_encode_round_trips() {
+    let (trie, _) = sample(186, 300);
+    let dense: Trie<u8> = (0..1000).map(|key| (key, (key / 7 % 3) as u8)).collect();
+
+    for trie in &[trie.try_map(|_, &value| Ok::<_, ()>((value % 3) as u8)).unwrap(), dense] {
+        let runs = trie.run_length_encode();
+
+        assert!(runs.windows(2).all(|pair| pair[0].0 + pair[0].1 <= pair[1].0));
+        assert_eq!(runs.iter().map(|run| run.1).sum::<usize>(), trie.len());
+        assert_eq!(&Trie::from_run_length(runs), trie);
+    }
+}
+
+#[test]
+fn run_length_encode_merges_consecutive_equal_values() {
+    let trie: Trie<char> = vec![(0, 'a'), (1, 'a'), (2, 'b'), (3, 'b'), (5, 'b'), (usize::MAX, 'c')].into_iter().collect();
+
+    assert_eq!(trie.run_length_encode(), vec![(0, 2, 'a'), (2, 2, 'b'), (5, 1, 'b'), (usize::MAX, 1, 'c')]);
+    assert!(Trie::<u8>::new().run_length_encode().is_empty());
+    assert!(Trie::<u8>::from_run_length(vec![(3, 0, 1)]).is_empty());
+}
+
+#[test]
+fn iteration_order_is_independent_of_insertion_order() {
+    for seed in 0..30 {
+        let mut rng = Rng(187 + seed);
+        let mut pairs: Vec<(usize, u64)> = (0..500).map(|value| (rng.key(), value)).collect();
+        pairs.sort_by_key(|pair| pair.0);
+        pairs.dedup_by_key(|pair| pair.0);
+
+        let mut shuffled = pairs.clone();
+        for index in (1..shuffled.len()).rev() {
+            shuffled.swap(index, rng.next() as usize % (index + 1));
+        }
+
+        let sorted_trie: Trie<u64> = pairs.iter().cloned().collect();
+        let shuffled_trie = shuffled.iter().fold(Trie::new(), |trie, &(key, value)| trie.update(key, value));
+        let sorted_entries = entries(&sorted_trie);
+
+        assert!(sorted_entries.windows(2).all(|pair| pair[0].0 < pair[1].0));
+        assert_eq!(sorted_entries, pairs);
+        assert_eq!(entries(&shuffled_trie), sorted_entries);
+        assert_eq!(shuffled_trie.clone().into_iter().collect::<Vec<_>>(), sorted_entries);
+        assert!(shuffled_trie.iter().rev().map(|(key, _)| key).eq(pairs.iter().rev().map(|pair| pair.0)));
+    }
+}
+
+#[test]
+fn retain_returning_moves_out_the_removed_entries() {
+    let (trie, reference) = sample(188, 300);
+    let mut trie_mut = trie.to_mut();
+    let removed = trie_mut.retain_returning(|_, value| {
+        *value += 1;
+        *value % 2 == 0
+    });
+
+    let kept = trie_mut.into_trie();
+    let expected_kept: Vec<_> = reference.iter().map(|(&k, &v)| (k, v + 1)).filter(|entry| entry.1 % 2 == 0).collect();
+    let expected_removed: Vec<_> = reference.iter().map(|(&k, &v)| (k, v + 1)).filter(|entry| entry.1 % 2 == 1).collect();
+
+    assert!(kept.validate());
+    assert_eq!(entries(&kept), expected_kept);
+    assert_eq!(removed, expected_removed);
+    assert_eq!(entries(&trie), reference.into_iter().collect::<Vec<_>>());
+}
+
+#[test]
+fn retain_returning_of_every_and_no_entry() {
+    let mut trie_mut = trie_of(&[1, 2, 3]).to_mut();
+
+    assert!(trie_mut.retain_returning(|_, _| true).is_empty());
+    assert_eq!(trie_mut.retain_returning(|_, _| false), vec![(1, 1), (2, 2), (3, 3)]);
+    assert!(trie_mut.into_trie().is_empty());
+}
+
+#[test]
+fn to_hashmap_and_to_btreemap_hold_every_entry() {
+    let (trie, reference) = sample(189, 300);
+    let hashmap = trie.to_hashmap();
+
+    assert_eq!(trie.to_btreemap(), reference);
+    assert_eq!(hashmap.len(), reference.len());
+    assert!(hashmap.capacity() >= trie.len());
+    assert!(reference.iter().all(|(key, value)| hashmap.get(key) == Some(value)));
+    assert!(Trie::<u8>::new().to_hashmap().is_empty());
+}
+
+#[test]
+fn update_all_with_combines_conflicting_values() {
+    let (trie, mut reference) = sample(190, 300);
+    let mut rng = Rng(190);
+    let keys: Vec<usize> = reference.keys().cloned().collect();
+    let updates: Vec<(usize, u64)> = (0..500)
+        .map(|round| (if round % 2 == 0 { keys[rng.next() as usize % keys.len()] } else { rng.key() }, round))
+        .collect();
+
+    let combined = trie.update_all_with(updates.iter().cloned(), |existing, new| existing + new);
+    for &(key, value) in &updates {
+        *reference.entry(key).or_insert(0) += value;
+    }
+
+    assert!(combined.validate());
+    assert_eq!(entries(&combined), reference.into_iter().collect::<Vec<_>>());
+}
+
+#[test]
+fn update_all_with_combines_repeated_keys_of_the_input() {
+    let trie = trie_of(&[1]);
+    let combined = trie.update_all_with(vec![(2, 10), (1, 5), (2, 20)], |existing, new| existing * 100 + new);
+
+    assert_eq!(entries(&combined), vec![(1, 105), (2, 1020)]);
+    assert_eq!(trie.update_all_with(vec![], |_, new| new), trie);
+}
+
+#[test]
+fn compact_of_a_valid_trie_shares_every_node() {
+    let (trie, _) = sample(191, 300);
+    let mut trie_mut = trie.to_mut();
+    for key in 0..100 {
+        trie_mut.insert(key * 31, key as u64);
+        trie_mut.remove(key * 17);
+    }
+    let edited = trie_mut.into_trie();
+    let compacted = edited.compact();
+
+    assert!(compacted.validate());
+    assert!(compacted.shares_root(&edited));
+    assert_eq!(compacted.unique_node_count(), edited.unique_node_count());
+    assert!(Trie::<u8>::new().compact().is_empty());
+}
+
+#[test]
+fn compact_does_not_grow_the_heap_size() {
+    let (trie, _) = sample(191, 300);
+    let edited = trie.remove_all(trie.iter().map(|(key, _)| key).step_by(2).collect::<Vec<_>>());
+    let compacted = edited.compact();
+
+    assert_eq!(compacted, edited);
+    assert!(compacted.heap_size() <= edited.heap_size());
+    assert!(trie.heap_size() > compacted.heap_size());
+    assert_eq!(Trie::<u8>::new().heap_size(), 0);
+    assert_eq!(trie_of(&[1]).heap_size(), 0);
+}
+
+#[test]
+fn first_difference_finds_the_least_differing_key() {
+    let (trie, reference) = sample(192, 300);
+    let keys: Vec<usize> = reference.keys().cloned().collect();
+    let (low, high) = (keys[40], keys[200]);
+    let changed = trie.update(high, 0).update(low, u64::MAX);
+
+    assert_eq!(trie.first_difference(&changed), Some(low));
+    assert_eq!(changed.first_difference(&trie), Some(low));
+    assert_eq!(trie.first_difference(&trie.remove(high)), Some(high));
+    assert_eq!(trie.first_difference(&trie.update(424_242, 0)), Some(424_242));
+}
+
+#[test]
+fn first_difference_of_equal_tries() {
+    let (trie, reference) = sample(192, 300);
+    let rebuilt: Trie<u64> = reference.into_iter().collect();
+
+    assert_eq!(trie.first_difference(&trie.clone()), None);
+    assert_eq!(trie.first_difference(&rebuilt), None);
+    assert_eq!(trie.first_difference(&Trie::new()), trie.min_key());
+    assert_eq!(Trie::<u8>::new().first_difference(&Trie::new()), None);
+}
+
+#[test]
+fn replace_overwrites_present_keys_only() {
+    let (trie, reference) = sample(193, 300);
+    let key = *reference.keys().nth(120).unwrap();
+    let replaced = trie.replace(key, 0).unwrap();
+
+    assert!(replaced.validate());
+    assert_eq!(replaced, trie.update(key, 0));
+    assert_eq!(replaced.unique_node_count(), trie.unique_node_count());
+    assert_eq!(trie.replace(424_242, 0), None);
+    assert_eq!(Trie::<u8>::new().replace(1, 1), None);
+}
+
+#[test]
+fn replace_of_a_single_entry() {
+    assert_eq!(trie_of(&[7]).replace(7, 70), Some(Trie::new().update(7, 70)));
+    assert_eq!(trie_of(&[7]).replace(8, 80), None);
+}
+
+#[test]
+fn keys_above_keeps_the_keys_above() {
+    let (trie, reference) = sample(194, 500);
+    let probes: Vec<usize> = reference.keys().cloned().step_by(23)
+        .chain(vec![0, 1, 12345, usize::MAX - 1, usize::MAX])
+        .collect();
+
+    for &bound in &probes {
+        let above = trie.keys_above(bound);
+        let expected: Vec<_> = reference.iter().filter(|entry| *entry.0 > bound).map(|(&k, &v)| (k, v)).collect();
+
+        assert!(above.validate(), "above {}", bound);
+        assert_eq!(above.len(), expected.len());
+        assert_eq!(entries(&above), expected);
+    }
+}
+
+#[test]
+fn keys_above_shares_the_subtrees_above() {
+    static COMPARED: AtomicUsize = AtomicUsize::new(0);
+
+    #[derive(Debug, Clone)]
+    struct Compared(usize);
+
+    impl PartialEq for Compared {
+        fn eq(&self, other: &Compared) -> bool {
+            COMPARED.fetch_add(1, Ordering::SeqCst);
+            self.0 == other.0
+        }
+    }
+
+    // 40 keys under each child of the root, cut within the 21st child
+    let trie: Trie<Compared> = (0..32 * 40)
+        .map(|key| ((key % 32) << (usize::BITS - 5) | key, Compared(key)))
+        .collect();
+    let bound = 20 << (usize::BITS - 5) | 700;
+    let above = trie.keys_above(bound);
+
+    assert!(above.validate());
+    assert_eq!(above.len(), 11 * 40 + 18);
+
+    // Only the leaves of the rebuilt boundary child are compared
+    assert!(above == trie.keys_above(bound));
+    assert_eq!(COMPARED.load(Ordering::SeqCst), 10);
+}
+
+#[test]
+fn update_all_returning_reports_the_overwritten_values() {
+    let trie = trie_of(&[1, 2, 3]);
+    let (updated, displaced) = trie.update_all_returning(vec![(2, 20), (5, 50), (3, 30), (5, 500)]);
+
+    assert!(updated.validate());
+    assert_eq!(entries(&updated), vec![(1, 1), (2, 20), (3, 30), (5, 500)]);
+    assert_eq!(displaced, vec![(2, 2), (3, 3), (5, 50)]);
+}
+
+#[test]
+fn update_all_returning_of_fresh_keys() {
+    let (trie, reference) = sample(195, 300);
+    let (updated, displaced) = trie.update_all_returning((0..50).map(|key| (key * 7919 + 424_242, 0)));
+
+    assert!(displaced.is_empty());
+    assert_eq!(updated.len(), reference.len() + 50);
+    assert_eq!(trie.update_all_returning(vec![]).0, trie);
+}
+
+#[test]
+fn remove_all_returning_reports_the_removed_values() {
+    let trie = trie_of(&[1, 2, 3, 40]);
+    let (remaining, removed) = trie.remove_all_returning(vec![40, 7, 2, 2, 8]);
+
+    assert!(remaining.validate());
+    assert_eq!(entries(&remaining), vec![(1, 1), (3, 3)]);
+    assert_eq!(removed, vec![(40, 40), (2, 2)]);
+}
+
+#[test]
+fn remove_all_returning_matches_remove_all() {
+    let (trie, reference) = sample(196, 300);
+    let keys: Vec<usize> = reference.keys().cloned().step_by(3).chain(vec![424_242]).collect();
+    let (remaining, removed) = trie.remove_all_returning(keys.iter().cloned());
+
+    assert_eq!(remaining, trie.remove_all(keys.iter().cloned()));
+    assert_eq!(removed, keys[..keys.len() - 1].iter().map(|key| (*key, reference[key])).collect::<Vec<_>>());
+    assert_eq!(trie.remove_all_returning(vec![424_242]).0, trie);
+}
+
+#[test]
+fn map_reduce_matches_an_iterator_fold() {
+    let (trie, reference) = sample(197, 300);
+
+    assert_eq!(trie.map_reduce(|_, &value| value, |a, b| a + b), Some(reference.values().sum()));
+    assert_eq!(trie.map_reduce(|key, _| key, |a, b| a.max(b)), trie.max_key());
+    assert_eq!(trie.map_reduce(|_, _| 1, |a, b| a + b), Some(trie.len()));
+}
+
+#[test]
+fn map_reduce_combines_in_key_order() {
+    let (trie, reference) = sample(197, 100);
+    let keys = trie.map_reduce(|key, _| vec![key], |mut a, b| {
+        a.extend(b);
+        a
+    });
+
+    assert_eq!(keys, Some(reference.keys().cloned().collect()));
+    assert_eq!(Trie::<u8>::new().map_reduce(|_, _| 1, |a, b| a + b), None);
+    assert_eq!(trie_of(&[9]).map_reduce(|key, _| key, |a, b| a + b), Some(9));
+}
+
+#[test]
+fn get_or_insert_default_of_absent_keys() {
+    let trie = trie_of(&[1, 2]);
+    let (ensured, value) = trie.get_or_insert_default(5);
+
+    assert_eq!(value, 0);
+    assert_eq!(entries(&ensured), vec![(1, 1), (2, 2), (5, 0)]);
+    assert_eq!(entries(&trie), vec![(1, 1), (2, 2)]);
+    assert_eq!(Trie::<String>::new().get_or_insert_default(3).0.get(3), Some(String::new()));
+}
+
+#[test]
+fn get_or_insert_default_of_present_keys() {
+    let (trie, reference) = sample(198, 300);
+    let key = *reference.keys().nth(10).unwrap();
+    let (ensured, value) = trie.get_or_insert_default(key);
+
+    assert_eq!(value, reference[&key]);
+    assert!(ensured.shares_root(&trie));
+}
+
+#[test]
+fn node_counts_of_known_shapes() {
+    let depth = (usize::BITS as usize).div_ceil(5);
+    let top = 1 << (usize::BITS - 1);
+    let spread: Vec<_> = (0..16).map(|top| top << (usize::BITS - 4)).collect();
+
+    assert_eq!(Trie::<u8>::new().node_counts(), (0, 0));
+    assert_eq!(trie_of(&[42]).node_counts(), (1, 0));
+    assert_eq!(trie_of(&spread).node_counts(), (16, 1));
+    assert_eq!(trie_of(&[0, 1]).node_counts(), (2, depth));
+    assert_eq!(trie_of(&[0, 1, top, top + 1]).node_counts(), (4, 1 + 2 * (depth - 1)));
+}
+
+#[test]
+fn node_counts_count_every_entry_as_a_leaf() {
+    let (trie, _) = sample(199, 500);
+    let (leaves, inner) = trie.node_counts();
+
+    assert_eq!(leaves, trie.len());
+    assert!(inner >= 1 && inner < leaves);
+}
+
+#[test]
+fn fork_region_detaches_a_copy_of_the_region() {
+    let (trie, reference) = sample(200, 500);
+    let (full, region) = trie.fork_region(50, 1 << 40);
+
+    assert!(full.shares_root(&trie));
+    assert!(region.validate());
+    assert_eq!(entries(&region), reference.range(50..1 << 40).map(|(&k, &v)| (k, v)).collect::<Vec<_>>());
+    assert!(region.iter().all(|(key, _)| region.leaf_ptr(key) != trie.leaf_ptr(key)));
+}
+
+#[test]
+fn fork_region_round_trips_through_splice() {
+    let (trie, _) = sample(200, 500);
+    let (full, region) = trie.fork_region(50, 1 << 40);
+    let processed = region.try_map(|_, value| Ok::<_, ()>(value * 2)).unwrap();
+    let gathered = full.splice(50..1 << 40, processed.iter().map(|(key, &value)| (key, value)));
+
+    assert_eq!(entries(&full), entries(&trie));
+    assert!(gathered.validate());
+    assert_eq!(gathered, trie.retain(|key, _| !(50..1 << 40).contains(&key))
+        .update_all(processed.iter().map(|(key, &value)| (key, value))));
+    assert!(trie.fork_region(9, 9).1.is_empty());
+}
+
+#[test]
+fn diff_iter_matches_a_comparison_of_maps() {
+    let (trie, before) = sample(201, 300);
+    let keys: Vec<usize> = before.keys().cloned().collect();
+    let mut rng = Rng(201);
+    let mut trie_mut = trie.to_mut();
+
+    for round in 0..60 {
+        let key = if round % 2 == 0 { keys[rng.next() as usize % keys.len()] } else { rng.key() };
+        if round % 3 == 0 { trie_mut.remove(key); } else { trie_mut.insert(key, round); }
+    }
+    let other = trie_mut.into_trie();
+    let after = other.to_btreemap();
+
+    let mut expected = Vec::new();
+    for key in before.keys().chain(after.keys()).cloned().collect::<BTreeSet<_>>() {
+        match (before.get(&key), after.get(&key)) {
+            (Some(old), None) => expected.push(Change::Removed(key, old)),
+            (None, Some(new)) => expected.push(Change::Added(key, new)),
+            (Some(old), Some(new)) if old != new => expected.push(Change::Modified(key, old, new)),
+            _ => {}
+        }
+    }
+
+    assert!(!expected.is_empty());
+    assert_eq!(trie.diff_iter(&other).collect::<Vec<_>>(), expected);
+}
+
+#[test]
+fn diff_iter_of_equal_and_empty_tries() {
+    let (trie, _) = sample(201, 300);
+
+    assert_eq!(trie.diff_iter(&trie.clone()).next(), None);
+    assert_eq!(trie.diff_iter(&Trie::new()).count(), trie.len());
+    assert!(Trie::new().diff_iter(&trie).all(|change| matches!(change, Change::Added(..))));
+    assert_eq!(trie_of(&[1]).diff_iter(&trie_of(&[2])).collect::<Vec<_>>(), vec![Change::Removed(1, &1), Change::Added(2, &2)]);
+}
+
+#[test]
+fn new_with_root_capacity_builds_the_same_trie() {
+    let (trie, reference) = sample(202, 500);
+    let mut trie_mut = TrieMut::new_with_root_capacity(32);
+    trie_mut.extend(reference.iter().map(|(&key, &value)| (key, value)));
+
+    let built = trie_mut.into_trie();
+    assert!(built.validate());
+    assert_eq!(built, trie);
+}
+
+#[test]
+fn new_with_root_capacity_of_few_entries() {
+    assert!(TrieMut::<u8>::new_with_root_capacity(1000).into_trie().is_empty());
+    assert!(TrieMut::<u8>::new_with_root_capacity(0).into_trie().validate());
+
+    let mut trie_mut = TrieMut::new_with_root_capacity(32);
+    trie_mut.insert(7, 7);
+    assert_eq!(trie_mut.len(), 1);
+
+    let single = trie_mut.into_trie();
+    assert!(single.validate());
+    assert_eq!(single, trie_of(&[7]));
+}
+
+#[test]
+fn update_checked_reports_the_kind_of_update() {
+    let (trie, reference) = sample(203, 300);
+    let key = *reference.keys().nth(42).unwrap();
+
+    let (inserted, kind) = trie.update_checked(424_242, 1);
+    assert_eq!(kind, UpdateKind::Inserted);
+    assert_eq!(inserted.get(424_242), Some(1));
+
+    let (replaced, kind) = trie.update_checked(key, reference[&key] + 1);
+    assert_eq!(kind, UpdateKind::Replaced);
+    assert_eq!(replaced, trie.update(key, reference[&key] + 1));
+}
+
+#[test]
+fn update_checked_with_an_equal_value_shares_the_root() {
+    let (trie, reference) = sample(203, 300);
+    let key = *reference.keys().nth(42).unwrap();
+    let (unchanged, kind) = trie.update_checked(key, reference[&key]);
+
+    assert_eq!(kind, UpdateKind::Unchanged);
+    assert!(unchanged.shares_root(&trie));
+    assert_eq!(Trie::new().update_checked(3, 3).1, UpdateKind::Inserted);
+}
+
+#[test]
+fn occupied_ranges_of_isolated_keys_and_blocks() {
+    let keys: Vec<usize> = vec![0, 1, 2, 5].into_iter().chain(10..40).chain(vec![42, 100]).chain(usize::MAX - 2..=usize::MAX).collect();
+    let trie = trie_of(&keys);
+
+    assert_eq!(trie.occupied_ranges(), vec![0..=2, 5..=5, 10..=39, 42..=42, 100..=100, usize::MAX - 2..=usize::MAX]);
+    assert!(Trie::<u8>::new().occupied_ranges().is_empty());
+}
+
+#[test]
+fn occupied_ranges_match_a_brute_force_scan() {
+    let mut rng = Rng(204);
+    let keys: Vec<usize> = (0..400).map(|_| rng.next() as usize % 1000).collect();
+    let trie = trie_of(&keys);
+
+    let mut expected: Vec<std::ops::RangeInclusive<usize>> = Vec::new();
+    for key in (0..1000).filter(|key| keys.contains(key)) {
+        match expected.last_mut() {
+            Some(range) if *range.end() + 1 == key => *range = *range.start()..=key,
+            _ => expected.push(key..=key),
+        }
+    }
+
+    assert_eq!(trie.occupied_ranges(), expected);
+}
@@ -0,0 +1,94 @@
+extern crate elsa;
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::ptr;
+
+use elsa::trie::Trie;
+
+thread_local! {
+    static FAIL_ALLOC: Cell<bool> = const { Cell::new(false) };
+}
+
+/// System allocator which fails every allocation of the current thread on demand
+struct FailingAlloc;
+
+unsafe impl GlobalAlloc for FailingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if FAIL_ALLOC.with(Cell::get) {
+            ptr::null_mut()
+        } else {
+            System.alloc(layout)
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOC: FailingAlloc = FailingAlloc;
+
+fn failing<R, F: FnOnce() -> R>(f: F) -> R {
+    FAIL_ALLOC.with(|fail| fail.set(true));
+    let res = f();
+    FAIL_ALLOC.with(|fail| fail.set(false));
+    res
+}
+
+#[test]
+fn try_insert_behaves_like_insert() {
+    let mut trie_mut = Trie::new().to_mut();
+
+    for value in 0..100 {
+        assert_eq!(trie_mut.try_insert(value * 977, value), Ok(None));
+    }
+
+    assert_eq!(trie_mut.try_insert(0, 5), Ok(Some(0)));
+
+    let trie = trie_mut.into_trie();
+    assert_eq!(trie.len(), 100);
+    assert_eq!(trie.get(977 * 3), Some(3));
+    assert_eq!(trie.get(0), Some(5));
+}
+
+#[test]
+fn try_insert_reports_failed_reservation() {
+    let trie = Trie::new().update(1, 1).update(1 << 40, 2);
+    let mut trie_mut = trie.to_mut();
+
+    assert!(failing(|| trie_mut.try_insert(2, 3)).is_err());
+    assert_eq!(trie_mut.len(), 2);
+
+    assert_eq!(trie_mut.try_insert(2, 3), Ok(None));
+    assert_eq!(trie_mut.into_trie().get(2), Some(3));
+}
+
+#[test]
+fn try_insert_reports_failed_leaf_split() {
+    let mut trie_mut = Trie::new().update(7, 'a').to_mut();
+
+    assert!(failing(|| trie_mut.try_insert(8, 'b')).is_err());
+
+    let trie = trie_mut.into_trie();
+    assert_eq!(trie.len(), 1);
+    assert_eq!(trie.get(7), Some('a'));
+    assert_eq!(trie.get(8), None);
+}
+
+#[test]
+fn try_insert_error_propagates_with_question_mark() {
+    fn build(keys: &[usize]) -> Result<Trie<usize>, std::collections::TryReserveError> {
+        let mut trie_mut = Trie::new().to_mut();
+
+        for &key in keys {
+            trie_mut.try_insert(key, key)?;
+        }
+
+        Ok(trie_mut.into_trie())
+    }
+
+    assert_eq!(build(&[1, 2, 3]).unwrap().len(), 3);
+    assert!(failing(|| build(&[1, 2]).map(|trie| trie.len())).is_err());
+}